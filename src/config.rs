@@ -0,0 +1,367 @@
+use crate::environment::Environment;
+use crate::filepacker::Mtime;
+use crate::fsutil::{self, PermissionPolicy};
+use crate::vault::VaultMode;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the project configuration file
+pub const CONFIG_FILE: &str = "envbuddel.toml";
+
+/// Content of `envbuddel.toml`. The top level configures the default environment,
+/// `[environments.<name>]` the named ones. Missing settings fall back to the conventional defaults.
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyfile: Option<PathBuf>,
+    /// Environment variable the key is read from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_var: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env_conf: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vault: Option<PathBuf>,
+    /// Vault mode used when no vault exists yet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<VaultMode>,
+    /// `warn` or `deny` when the keyfile or plaintext is readable by other users
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<PermissionPolicy>,
+    /// `preserve` or `zero` the modification times of packed folders
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<Mtime>,
+    /// Modes `decrypt` gives paths inside a folder environment, e.g. `"id_rsa.pub" = "0644"`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub modes: BTreeMap<PathBuf, FileMode>,
+    /// Gitignore-style patterns excluding files of a folder environment from the vault
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub environments: BTreeMap<String, Config>,
+}
+
+/// Octal file mode written as string, e.g. `"0600"`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(try_from = "String", into = "String")]
+pub struct FileMode(pub u32);
+
+impl TryFrom<String> for FileMode {
+    type Error = String;
+
+    fn try_from(mode: String) -> Result<Self, Self::Error> {
+        let digits = mode.strip_prefix("0o").unwrap_or(&mode);
+        u32::from_str_radix(digits, 8)
+            .ok()
+            .filter(|bits| *bits <= 0o7777)
+            .map(FileMode)
+            .ok_or_else(|| format!("Invalid file mode {:?}, expected e.g. \"0600\"", mode))
+    }
+}
+
+impl From<FileMode> for String {
+    fn from(mode: FileMode) -> Self {
+        format!("{:04o}", mode.0)
+    }
+}
+
+/// A loaded configuration file
+pub struct Project {
+    /// Path of the configuration file
+    pub path: PathBuf,
+    /// Directory the paths in the configuration are relative to
+    pub dir: PathBuf,
+    pub config: Config,
+}
+
+impl Config {
+    /// Parse and validate the configuration
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let config: Config = toml::from_str(content).map_err(|e| e.to_string())?;
+        for (name, environment) in &config.environments {
+            Environment::named(Some(name))?;
+            if !environment.environments.is_empty() {
+                return Err(format!(
+                    "Environment {:?} cannot contain environments",
+                    name
+                ));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Settings of the named environment, or of the default environment
+    pub fn settings(&self, name: Option<&str>) -> Option<&Config> {
+        match name {
+            Some(name) => self.environments.get(name),
+            None => Some(self),
+        }
+    }
+
+    /// Settings describing `environment`, with paths relative to `dir`
+    pub fn describe(environment: &Environment, mode: VaultMode, dir: &Path) -> Self {
+        Self {
+            keyfile: Some(relative(&environment.keyfile, dir)),
+            key_var: Some(environment.key_var.clone()),
+            env_conf: Some(relative(&environment.env_conf, dir)),
+            vault: Some(relative(&environment.vault, dir)),
+            mode: Some(mode),
+            permissions: None,
+            mtime: None,
+            modes: BTreeMap::new(),
+            exclude: Vec::new(),
+            environments: BTreeMap::new(),
+        }
+    }
+}
+
+impl Project {
+    /// Find `envbuddel.toml` in the current or a parent directory
+    pub fn find() -> Result<Option<Self>, String> {
+        match fsutil::find_upwards(Path::new("."), CONFIG_FILE, Path::is_file) {
+            Some(dir) => Self::load(&dir).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Load `envbuddel.toml` from `dir`
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        let path = dir.join(CONFIG_FILE);
+        let content =
+            fs::read_to_string(&path).map_err(|e| format!("Failed to read {:?}: {}", path, e))?;
+        let config = Config::parse(&content).map_err(|e| format!("Invalid {:?}: {}", path, e))?;
+
+        // Keep paths short when working in the project directory
+        let dir = match std::env::current_dir().and_then(|cwd| cwd.canonicalize()) {
+            Ok(cwd) if cwd == dir => PathBuf::new(),
+            _ => dir.to_path_buf(),
+        };
+        let path = dir.join(CONFIG_FILE);
+        Ok(Self { path, dir, config })
+    }
+
+    /// Apply the settings of the environment on top of its conventional defaults
+    pub fn configure(&self, environment: &mut Environment) {
+        let Some(settings) = self.config.settings(environment.name.as_deref()) else {
+            return;
+        };
+        if let Some(keyfile) = &settings.keyfile {
+            environment.keyfile = self.dir.join(keyfile);
+        }
+        if let Some(key_var) = &settings.key_var {
+            environment.key_var = key_var.clone();
+        }
+        if let Some(env_conf) = &settings.env_conf {
+            environment.env_conf = self.dir.join(env_conf);
+        }
+        if let Some(vault) = &settings.vault {
+            environment.vault = self.dir.join(vault);
+        }
+        environment.mode = settings.mode;
+        if let Some(permissions) = settings.permissions {
+            environment.permissions = permissions;
+        }
+        if let Some(mtime) = settings.mtime {
+            environment.pack.mtime = mtime;
+        }
+        environment.pack.modes = settings
+            .modes
+            .iter()
+            .map(|(path, mode)| (path.clone(), mode.0))
+            .collect();
+        environment.pack.exclude = settings.exclude.clone();
+    }
+
+    /// Whether the configuration has settings for the environment
+    pub fn contains(&self, environment: &Environment) -> bool {
+        match &environment.name {
+            Some(name) => self.config.environments.contains_key(name),
+            None => {
+                let config = &self.config;
+                config.keyfile.is_some()
+                    || config.key_var.is_some()
+                    || config.env_conf.is_some()
+                    || config.vault.is_some()
+            }
+        }
+    }
+
+    /// Add the environment to the configuration file, keeping its content and comments
+    pub fn add(&self, environment: &Environment, mode: VaultMode) -> Result<(), String> {
+        let settings = Config::describe(environment, mode, &self.absolute_dir()?);
+        let rendered = render(environment.name.as_deref(), settings)?;
+        let content = fs::read_to_string(&self.path)
+            .map_err(|e| format!("Failed to read {:?}: {}", self.path, e))?;
+
+        // Top level settings must come before the first table, named environments are appended
+        let content = if environment.name.is_none() {
+            let header: usize = content
+                .split_inclusive('\n')
+                .take_while(|line| line.trim().is_empty() || line.trim_start().starts_with('#'))
+                .map(str::len)
+                .sum();
+            format!("{}{}\n{}", &content[..header], rendered, &content[header..])
+        } else {
+            let separator = if content.is_empty() || content.ends_with('\n') {
+                "\n"
+            } else {
+                "\n\n"
+            };
+            format!("{}{}{}", content, separator, rendered)
+        };
+        fsutil::write_atomic(&self.path, content.as_bytes())
+    }
+
+    /// Directory of the configuration file
+    pub fn absolute_dir(&self) -> Result<PathBuf, String> {
+        if self.dir.as_os_str().is_empty() {
+            std::env::current_dir().map_err(|e| format!("Failed to get current dir: {}", e))
+        } else {
+            Ok(self.dir.clone())
+        }
+    }
+}
+
+/// Write a new `envbuddel.toml` describing the environment to the current directory
+pub fn create(environment: &Environment, mode: VaultMode) -> Result<PathBuf, String> {
+    let dir = std::env::current_dir().map_err(|e| format!("Failed to get current dir: {}", e))?;
+    let path = PathBuf::from(CONFIG_FILE);
+    let content = format!(
+        "# envbuddel project configuration. Paths are relative to this file.\n\n{}",
+        render(
+            environment.name.as_deref(),
+            Config::describe(environment, mode, &dir)
+        )?
+    );
+    fsutil::write_atomic(&path, content.as_bytes())?;
+    Ok(path)
+}
+
+/// TOML of the settings, in an `[environments.<name>]` table for named environments
+fn render(name: Option<&str>, settings: Config) -> Result<String, String> {
+    let config = match name {
+        Some(name) => Config {
+            environments: BTreeMap::from([(name.to_string(), settings)]),
+            ..Config::default()
+        },
+        None => settings,
+    };
+    toml::to_string(&config).map_err(|e| format!("Failed to write configuration: {}", e))
+}
+
+/// Express `path` relative to `dir` where possible
+fn relative(path: &Path, dir: &Path) -> PathBuf {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    match absolute.strip_prefix(dir) {
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+keyfile = "keys/dev.key"
+key_var = "APP_SECRET"
+mode = "values"
+permissions = "deny"
+
+[environments.prod]
+vault = "vaults/prod.enc"
+mtime = "zero"
+exclude = ["*.swp", ".git/"]
+
+[environments.prod.modes]
+"ssh/id_rsa.pub" = "0644"
+"bin/deploy" = "0o755"
+"#;
+
+    // Test that the default and named environments take their settings
+    #[test]
+    fn test_configure() {
+        let project = Project {
+            path: PathBuf::from("/project").join(CONFIG_FILE),
+            dir: PathBuf::from("/project"),
+            config: Config::parse(CONFIG).unwrap(),
+        };
+
+        let mut default = Environment::named(None).unwrap();
+        project.configure(&mut default);
+        assert_eq!(default.keyfile, PathBuf::from("/project/keys/dev.key"));
+        assert_eq!(default.key_var, "APP_SECRET");
+        assert_eq!(default.vault, PathBuf::from("vault.enc"));
+        assert_eq!(default.mode, Some(VaultMode::Values));
+        assert_eq!(default.permissions, PermissionPolicy::Deny);
+
+        let mut prod = Environment::named(Some("prod")).unwrap();
+        project.configure(&mut prod);
+        assert_eq!(prod.vault, PathBuf::from("/project/vaults/prod.enc"));
+        assert_eq!(prod.keyfile, PathBuf::from("vault.prod.key"));
+        assert_eq!(prod.key_var, "CI_SECRET_PROD");
+        assert_eq!(prod.mode, None);
+        assert_eq!(prod.permissions, PermissionPolicy::Warn);
+        assert_eq!(prod.pack.mtime, Mtime::Zero);
+        assert_eq!(prod.pack.modes[Path::new("ssh/id_rsa.pub")], 0o644);
+        assert_eq!(prod.pack.modes[Path::new("bin/deploy")], 0o755);
+        assert_eq!(prod.pack.exclude, vec!["*.swp", ".git/"]);
+        assert_eq!(default.pack.mtime, Mtime::Preserve);
+        assert!(default.pack.modes.is_empty());
+    }
+
+    // Test that typos and invalid values are rejected
+    #[test]
+    fn test_parse_errors() {
+        assert!(Config::parse("keyfiel = \"x\"").is_err());
+        assert!(Config::parse("[environments.\"../x\"]").is_err());
+        assert!(Config::parse("[environments.a.environments.b]").is_err());
+        assert!(Config::parse("[modes]\n\"a\" = \"0999\"").is_err());
+        assert!(Config::parse("[modes]\n\"a\" = \"17777\"").is_err());
+        assert!(Config::parse("mtime = \"now\"").is_err());
+    }
+
+    // Test that environments are added to an existing file without losing its content
+    #[test]
+    fn test_add() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().canonicalize().unwrap();
+        fs::write(
+            dir.join(CONFIG_FILE),
+            "# comment\n\n[environments.dev]\nkey_var = \"DEV\"\n",
+        )
+        .unwrap();
+        let project = Project::load(&dir).unwrap();
+
+        let mut default = Environment::named(None).unwrap();
+        default.vault = dir.join("vault.enc");
+        project.add(&default, VaultMode::Blob).unwrap();
+        project
+            .add(
+                &Environment::named(Some("prod")).unwrap(),
+                VaultMode::Values,
+            )
+            .unwrap();
+
+        let content = fs::read_to_string(dir.join(CONFIG_FILE)).unwrap();
+        assert!(content.starts_with("# comment\n\n"));
+        let config = Config::parse(&content).unwrap();
+        assert_eq!(config.vault, Some(PathBuf::from("vault.enc")));
+        assert_eq!(config.environments["dev"].key_var.as_deref(), Some("DEV"));
+        assert_eq!(config.environments["prod"].mode, Some(VaultMode::Values));
+    }
+
+    // Test that written settings are read back
+    #[test]
+    fn test_render_roundtrip() {
+        let environment = Environment::named(Some("staging")).unwrap();
+        let dir = std::env::current_dir().unwrap();
+        let settings = Config::describe(&environment, VaultMode::Blob, &dir);
+        let config = Config::parse(&render(Some("staging"), settings.clone()).unwrap()).unwrap();
+
+        assert_eq!(config.environments["staging"], settings);
+        assert_eq!(settings.vault, Some(PathBuf::from("vault.staging.enc")));
+    }
+}
@@ -1,42 +1,184 @@
-use crate::crypto::KeySource::{Env, File};
+use crate::crypto::KeySource::{Env, File, ProtectedFile};
 use crate::filepacker::EnvironmentPack;
+use crate::fsutil::{self, PermissionPolicy};
+use crate::protect::{self, KdfParams};
+use crate::recipients::{self, Recipient};
+use crate::secret;
+use crate::values;
+use crate::vault::{self, Kdf, VaultHeader, VaultMode, KEY_ID_LEN};
 use base64::Engine;
+use hkdf::Hkdf;
 use rand::RngCore;
+use sha2::Sha256;
+use std::fmt;
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
+use zeroize::{Zeroize, Zeroizing};
 
-const BASE62: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+pub const BASE62: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
 
+/// Prefix of a printable X25519 identity
+pub const IDENTITY_PREFIX: &str = "ENVBUDDEL-IDENTITY-";
+
+/// HKDF info string of key ids, separating them from all other uses of the key
+const KEY_ID_INFO: &[u8] = b"envbuddel-key-id-v1";
+
+/// Key material is kept in locked memory and wiped on drop
 pub struct Key {
-    bytes: [u8; 32],
+    bytes: Box<[u8; 32]>,
+    kind: KeyKind,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum KeyKind {
+    /// Shared 32-byte AES key
+    Symmetric,
+    /// Private X25519 key of a vault recipient
+    Identity,
 }
 
 #[derive(Eq, PartialEq, Debug, Clone)]
 pub enum KeySource {
     File(PathBuf),
-    Env,
+    /// Keyfile encrypted under a passphrase
+    ProtectedFile(PathBuf),
+    /// Environment variable or option the key was passed in
+    Env(String),
+}
+
+impl fmt::Display for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            File(keyfile) => write!(f, "{:?}", keyfile),
+            ProtectedFile(keyfile) => write!(f, "passphrase-protected {:?}", keyfile),
+            Env(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        self.bytes.zeroize();
+        secret::unlock(&self.bytes[..]);
+    }
 }
 
 impl Key {
+    /// Locked, zeroed key material to fill in
+    fn empty(kind: KeyKind) -> Self {
+        let key = Self {
+            bytes: Box::new([0u8; 32]),
+            kind,
+        };
+        secret::lock(&key.bytes[..]);
+        key
+    }
+
     /// Generate a new random 32-byte key
     pub fn generate() -> Self {
-        let mut bytes = [0u8; 32];
-        rand::rng().fill_bytes(&mut bytes);
-        Self { bytes }
+        let mut key = Self::empty(KeyKind::Symmetric);
+        rand::rng().fill_bytes(&mut key.bytes[..]);
+        key
+    }
+
+    /// Generate a new random X25519 identity
+    pub fn generate_identity() -> Self {
+        let mut key = Self::generate();
+        key.kind = KeyKind::Identity;
+        key
+    }
+
+    /// Public recipient of an identity
+    pub fn recipient(&self) -> Option<Recipient> {
+        match self.kind {
+            KeyKind::Symmetric => None,
+            KeyKind::Identity => Some(recipients::public_key(&self.bytes)),
+        }
     }
 
-    pub fn load_key(key: &Option<String>, keyfile: &Path) -> Result<(Key, KeySource), String> {
+    /// Identifier of the key that does not reveal it, derived with HKDF-SHA256
+    pub fn key_id(&self) -> [u8; KEY_ID_LEN] {
+        let mut id = [0u8; KEY_ID_LEN];
+        Hkdf::<Sha256>::new(None, &self.bytes[..])
+            .expand(KEY_ID_INFO, &mut id)
+            .expect("8 bytes is a valid HKDF-SHA256 output length");
+        id
+    }
+
+    /// Printable key id, safe to show in logs
+    pub fn fingerprint(&self) -> String {
+        vault::fingerprint(&self.key_id())
+    }
+
+    /// Load the key passed in `key`, otherwise from the keyfile.
+    /// `key_var` names the environment variable or option `key` was read from.
+    /// `policy` decides what happens when the keyfile is readable by other users.
+    pub fn load_key(
+        key: &Option<String>,
+        key_var: &str,
+        keyfile: &Path,
+        policy: PermissionPolicy,
+    ) -> Result<(Key, KeySource), String> {
+        Key::load_key_with(key, key_var, keyfile, policy, || {
+            protect::read_passphrase(&format!("Passphrase for {}: ", keyfile.display()))
+        })
+    }
+
+    /// Like `load_key`, but `None` when neither a key was passed nor a keyfile exists yet.
+    /// Any other error is returned, so a keyfile that fails to load is never replaced.
+    pub fn load_existing_key(
+        key: &Option<String>,
+        key_var: &str,
+        keyfile: &Path,
+        policy: PermissionPolicy,
+    ) -> Result<Option<(Key, KeySource)>, String> {
+        if key.is_none() {
+            if let Err(e) = fs::symlink_metadata(keyfile) {
+                if e.kind() == io::ErrorKind::NotFound {
+                    return Ok(None);
+                }
+            }
+        }
+        Key::load_key(key, key_var, keyfile, policy).map(Some)
+    }
+
+    /// Like `load_key`, with `passphrase` asked for the passphrase of a protected keyfile
+    pub fn load_key_with(
+        key: &Option<String>,
+        key_var: &str,
+        keyfile: &Path,
+        policy: PermissionPolicy,
+        passphrase: impl Fn() -> Result<Zeroizing<String>, String>,
+    ) -> Result<(Key, KeySource), String> {
         if let Some(key) = key {
-            Ok((Key::from_printable(&key)?, Env))
+            let key = Key::from_printable(key)
+                .map_err(|e| format!("Invalid key in {}: {}", key_var, e))?;
+            Ok((key, Env(key_var.to_string())))
         } else {
             // Try to read the keyfile
-            match fs::read_to_string(keyfile) {
+            match fs::read_to_string(keyfile).map(Zeroizing::new) {
                 Ok(content) => {
-                    let trimmed = content.trim();
+                    if let Ok(metadata) = fs::metadata(keyfile) {
+                        policy.enforce(keyfile, fsutil::private_problem(&metadata))?;
+                    }
+
+                    // Comment lines are allowed, e.g. the recipient above an identity
+                    let trimmed = content
+                        .lines()
+                        .map(|line| line.trim())
+                        .find(|line| !line.is_empty() && !line.starts_with('#'))
+                        .unwrap_or("");
                     if trimmed.is_empty() {
                         Err(format!("Error: Keyfile {:?} is empty", keyfile))
+                    } else if protect::is_protected(trimmed) {
+                        let passphrase = passphrase()?;
+                        Ok((
+                            protect::unprotect(trimmed, &passphrase)?,
+                            ProtectedFile(keyfile.to_path_buf()),
+                        ))
                     } else {
-                        Ok((Key::from_printable(&trimmed)?, File(keyfile.to_path_buf())))
+                        Ok((Key::from_printable(trimmed)?, File(keyfile.to_path_buf())))
                     }
                 }
                 Err(e) => Err(format!(
@@ -48,7 +190,26 @@ impl Key {
     }
 
     pub fn save_key(&self, keyfile: &Path) -> Result<(), String> {
-        fs::write(keyfile, self.to_printable()).map_err(|e| e.to_string())
+        fsutil::write_private(keyfile, self.to_keyfile(None)?.as_bytes())
+    }
+
+    /// Save the key encrypted under a passphrase with Argon2id
+    pub fn save_protected_key(&self, keyfile: &Path, passphrase: &str) -> Result<(), String> {
+        fsutil::write_private(keyfile, self.to_keyfile(Some(passphrase))?.as_bytes())
+    }
+
+    /// Content of a keyfile holding this key, optionally protected by a passphrase
+    pub fn to_keyfile(&self, passphrase: Option<&str>) -> Result<Zeroizing<String>, String> {
+        let line = match passphrase {
+            Some(passphrase) => {
+                Zeroizing::new(protect::protect(self, passphrase, KdfParams::default())?)
+            }
+            None => self.to_printable(),
+        };
+        Ok(match self.recipient() {
+            Some(recipient) => Zeroizing::new(format!("# recipient: {}\n{}\n", recipient, *line)),
+            None => line,
+        })
     }
 
     /// Load key from raw bytes (must be 32 bytes)
@@ -59,9 +220,9 @@ impl Key {
                 bytes.len()
             ));
         }
-        let mut array = [0u8; 32];
-        array.copy_from_slice(bytes);
-        Ok(Self { bytes: array })
+        let mut key = Self::empty(KeyKind::Symmetric);
+        key.bytes.copy_from_slice(bytes);
+        Ok(key)
     }
 
     /// Load key from standard Base64
@@ -69,6 +230,7 @@ impl Key {
     pub fn from_base64(encoded: &str) -> Result<Self, String> {
         let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
             .decode(encoded)
+            .map(Zeroizing::new)
             .map_err(|e| format!("Failed to decode Base64 key: {}", e))?;
         Self::from_bytes(&bytes)
     }
@@ -76,33 +238,51 @@ impl Key {
     /// Get key as raw bytes
     #[allow(dead_code)]
     pub fn as_bytes(&self) -> &[u8] {
-        &self.bytes
+        &self.bytes[..]
     }
 
     /// Encode key as standard Base64
     #[allow(dead_code)]
-    pub fn to_base64(&self) -> String {
-        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&self.bytes)
+    pub fn to_base64(&self) -> Zeroizing<String> {
+        Zeroizing::new(base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&self.bytes[..]))
     }
 
-    pub fn to_printable(&self) -> String {
+    pub fn to_printable(&self) -> Zeroizing<String> {
         use base_x::encode;
-        encode(BASE62, &self.bytes)
+        let encoded = Zeroizing::new(encode(BASE62, &self.bytes[..]));
+        match self.kind {
+            KeyKind::Symmetric => encoded,
+            KeyKind::Identity => Zeroizing::new(format!("{}{}", IDENTITY_PREFIX, *encoded)),
+        }
     }
 
+    /// Load a Base62 symmetric key or an identity starting with `ENVBUDDEL-IDENTITY-`
     pub fn from_printable(encoded: &str) -> Result<Self, String> {
         use base_x::decode;
-        let bytes =
-            decode(BASE62, encoded).map_err(|e| format!("Failed to decode Base62: {}", e))?;
-        Self::from_bytes(&bytes)
+        let (encoded, kind) = match encoded.strip_prefix(IDENTITY_PREFIX) {
+            Some(identity) => (identity, KeyKind::Identity),
+            None => (encoded, KeyKind::Symmetric),
+        };
+        let bytes = decode(BASE62, encoded)
+            .map(Zeroizing::new)
+            .map_err(|e| format!("Failed to decode Base62: {}", e))?;
+        let mut key = Self::from_bytes(&bytes)?;
+        key.kind = kind;
+        Ok(key)
     }
 
     /// Encrypt a string and return ciphertext with prepended nonce
+    #[allow(dead_code)]
     pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, String> {
-        use aes_gcm::aead::{rand_core::RngCore, Aead, OsRng};
+        self.seal(plaintext, &[])
+    }
+
+    /// Encrypt and authenticate `aad` alongside, returning ciphertext with prepended nonce
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, String> {
+        use aes_gcm::aead::{rand_core::RngCore, Aead, OsRng, Payload};
         use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
 
-        let key = aes_gcm::Key::<Aes256Gcm>::from_slice(&self.bytes);
+        let key = aes_gcm::Key::<Aes256Gcm>::from_slice(&self.bytes[..]);
         let cipher = Aes256Gcm::new(key);
 
         let mut nonce_bytes = [0u8; 12];
@@ -110,7 +290,13 @@ impl Key {
         let nonce = Nonce::from_slice(&nonce_bytes);
 
         cipher
-            .encrypt(nonce, plaintext)
+            .encrypt(
+                nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
             .map(|mut ct| {
                 let mut result = nonce_bytes.to_vec();
                 result.append(&mut ct);
@@ -119,62 +305,157 @@ impl Key {
             .map_err(|e| format!("Encryption failed: {:?}", e))
     }
 
-    pub fn encrypt_base64(&self, pack: &EnvironmentPack) -> Result<String, String> {
-        use base64::{engine::general_purpose, Engine as _};
-
-        let ciphertext = self.encrypt(pack.to_bytes()?.as_slice())?;
-        let b64 = general_purpose::STANDARD.encode(&ciphertext);
-
-        // Wrap lines manually at 64 chars
-        let wrapped: String = b64
-            .chars()
-            .collect::<Vec<_>>()
-            .chunks(64)
-            .map(|chunk| chunk.iter().collect::<String>())
-            .collect::<Vec<_>>()
-            .join("\n");
-
-        Ok(wrapped)
-    }
-
-    /// Decrypt a ciphertext (with prepended nonce) back to a EnvironmentPack
-    pub fn decrypt(&self, ciphertext_with_nonce: &[u8]) -> Result<EnvironmentPack, String> {
-        use aes_gcm::aead::Aead;
+    /// Decrypt a ciphertext with prepended nonce and verify `aad`
+    pub fn open(
+        &self,
+        ciphertext_with_nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, String> {
+        use aes_gcm::aead::{Aead, Payload};
         use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
         if ciphertext_with_nonce.len() < 12 {
             return Err("Ciphertext too short: missing nonce".to_string());
         }
 
         let (nonce_bytes, ciphertext) = ciphertext_with_nonce.split_at(12);
-        let key = aes_gcm::Key::<Aes256Gcm>::from_slice(&self.bytes);
+        let key = aes_gcm::Key::<Aes256Gcm>::from_slice(&self.bytes[..]);
         let cipher = Aes256Gcm::new(key);
         let nonce = Nonce::from_slice(nonce_bytes);
 
         cipher
-            .decrypt(nonce, ciphertext)
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map(Zeroizing::new)
             .map_err(|_| {
                 "Decryption failed. Possible causes: wrong key, wrong nonce, or corrupted data."
                     .to_string()
             })
-            .and_then(|bytes| {
-                EnvironmentPack::from_bytes(&bytes).map_err(|e| format!("UTF-8 error: {}", e))
-            })
     }
 
-    pub fn decrypt_base64(&self, ciphertext_with_nonce: &str) -> Result<EnvironmentPack, String> {
-        // Remove any line breaks (LF or CRLF) before decoding
-        let cleaned = ciphertext_with_nonce.replace(&['\n', '\r'][..], "");
+    /// Encrypt the pack into an armored vault with a versioned header.
+    /// An identity encrypts the vault to its own recipient.
+    pub fn encrypt_base64(&self, pack: &EnvironmentPack) -> Result<String, String> {
+        if let Some(recipient) = self.recipient() {
+            return recipients::encrypt_base64(pack, &[recipient]);
+        }
 
-        // Decode Base64
-        let ciphertext_bytes = base64::engine::general_purpose::STANDARD
-            .decode(cleaned)
-            .map_err(|e| format!("Failed to decode Base64 ciphertext: {}", e))?;
+        let header = VaultHeader {
+            key_id: Some(self.key_id()),
+            ..VaultHeader::default()
+        }
+        .to_bytes();
+        let ciphertext = self.seal(pack.to_bytes()?.as_slice(), &header)?;
 
-        // Decrypt
-        self.decrypt(&ciphertext_bytes)
+        let mut vault = header;
+        vault.extend_from_slice(&ciphertext);
+        Ok(vault::encode_armor(&vault))
+    }
+
+    /// Encrypt the pack in the given vault mode.
+    /// `previous` is the current vault content, per-value vaults reuse unchanged ciphertexts from it.
+    pub fn encrypt_vault(
+        &self,
+        pack: &EnvironmentPack,
+        mode: VaultMode,
+        previous: Option<&str>,
+    ) -> Result<String, String> {
+        match (mode, pack) {
+            (VaultMode::Blob, _) => self.encrypt_base64(pack),
+            (VaultMode::Values, EnvironmentPack::File(content)) => {
+                values::encrypt(self, content, previous)
+            }
+            (VaultMode::Values, EnvironmentPack::Folder(_)) => {
+                Err("Per-value encryption only works with a single dotenv file".to_string())
+            }
+        }
+    }
+
+    /// Decrypt a headerless ciphertext (with prepended nonce) back to a EnvironmentPack
+    pub fn decrypt(&self, ciphertext_with_nonce: &[u8]) -> Result<EnvironmentPack, String> {
+        self.open(ciphertext_with_nonce, &[]).and_then(|bytes| {
+            EnvironmentPack::from_bytes(&bytes).map_err(|e| format!("UTF-8 error: {}", e))
+        })
+    }
+
+    /// Decrypt a vault. Per-value vaults and versioned vaults are recognized by their header,
+    /// everything else is treated as headerless legacy vault.
+    pub fn decrypt_base64(&self, armored: &str) -> Result<EnvironmentPack, String> {
+        if values::is_values_vault(armored) {
+            return values::decrypt(self, armored)
+                .map(|mut content| EnvironmentPack::File(std::mem::take(&mut *content)).locked());
+        }
+
+        let bytes = vault::decode_armor(armored)?;
+
+        match VaultHeader::parse(&bytes) {
+            Ok(None) => self
+                .decrypt(&bytes)
+                .map_err(|e| format!("{} (legacy vault)", e)),
+            Ok(Some((header, body))) => {
+                let data_key;
+                // Why the key is known to be right, so a failure can only mean corruption
+                let verified;
+                let cipher_key = match (header.kdf, self.kind) {
+                    (Kdf::None, KeyKind::Symmetric) => {
+                        verified = match &header.key_id {
+                            Some(key_id) if *key_id != self.key_id() => {
+                                return Err(wrong_key(&vault::fingerprint(key_id), self))
+                            }
+                            Some(_) => Some(format!("key {} matches the vault", self.fingerprint())),
+                            None => None,
+                        };
+                        self
+                    }
+                    (Kdf::None, KeyKind::Identity) => {
+                        return Err("Vault is encrypted with a symmetric key but an identity was supplied".to_string())
+                    }
+                    (Kdf::X25519, KeyKind::Identity) => {
+                        data_key = recipients::unwrap(&header.stanzas, &self.bytes)?;
+                        verified = Some("your identity is a recipient of the vault".to_string());
+                        &data_key
+                    }
+                    (Kdf::X25519, KeyKind::Symmetric) => {
+                        return Err(format!(
+                            "Vault is encrypted to recipients. Supply your identity ({}...) instead of a symmetric key",
+                            IDENTITY_PREFIX
+                        ))
+                    }
+                };
+
+                let header = &bytes[..bytes.len() - body.len()];
+                let plaintext = cipher_key.open(body, header).or_else(|err| {
+                    // A legacy nonce may start with the magic bytes by chance
+                    self.open(&bytes, &[]).map_err(|_| match &verified {
+                        Some(verified) => {
+                            format!(
+                                "Vault is corrupted: decryption failed although {}",
+                                verified
+                            )
+                        }
+                        None => err,
+                    })
+                })?;
+                EnvironmentPack::from_bytes(&plaintext)
+            }
+            Err(err) => self.decrypt(&bytes).map_err(|_| err),
+        }
     }
 }
 
+/// Error for a vault that records a different key than the supplied one
+pub fn wrong_key(vault_fingerprint: &str, key: &Key) -> String {
+    format!(
+        "Wrong key: the vault was encrypted with key {}, but key {} was supplied",
+        vault_fingerprint,
+        key.fingerprint()
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -228,12 +509,73 @@ mod tests {
     fn test_load_key_env() {
         let key = Key::generate();
         let key_str = key.to_printable();
-        let (loaded, source) =
-            Key::load_key(&Some(key_str.clone()), Path::new("/tmp/does_not_exist")).unwrap();
-        assert_eq!(source, KeySource::Env);
+        let (loaded, source) = Key::load_key(
+            &Some(key_str.to_string()),
+            "APP_SECRET",
+            Path::new("/tmp/does_not_exist"),
+            PermissionPolicy::Warn,
+        )
+        .unwrap();
+        assert_eq!(source, KeySource::Env("APP_SECRET".to_string()));
         assert_eq!(loaded.to_printable(), key_str);
     }
 
+    // Test that the deny policy refuses a keyfile readable by others
+    #[cfg(unix)]
+    #[test]
+    fn test_load_key_deny_readable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("key.txt");
+        Key::generate().save_key(&file_path).unwrap();
+        assert!(Key::load_key(&None, "CI_SECRET", &file_path, PermissionPolicy::Deny).is_ok());
+
+        std::fs::set_permissions(&file_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let result = Key::load_key(&None, "CI_SECRET", &file_path, PermissionPolicy::Deny);
+        assert!(result.is_err_and(|err| err.contains("group/others")));
+        assert!(Key::load_key(&None, "CI_SECRET", &file_path, PermissionPolicy::Warn).is_ok());
+    }
+
+    // Test that only a missing keyfile counts as no key, a keyfile that fails to load is an error
+    #[test]
+    fn test_load_existing_key() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("key.txt");
+        assert!(
+            Key::load_existing_key(&None, "CI_SECRET", &file_path, PermissionPolicy::Warn)
+                .unwrap()
+                .is_none()
+        );
+
+        std::fs::write(&file_path, "not a key\n").unwrap();
+        assert!(
+            Key::load_existing_key(&None, "CI_SECRET", &file_path, PermissionPolicy::Warn).is_err()
+        );
+
+        Key::generate().save_key(&file_path).unwrap();
+        let (_, source) =
+            Key::load_existing_key(&None, "CI_SECRET", &file_path, PermissionPolicy::Warn)
+                .unwrap()
+                .unwrap();
+        assert_eq!(source, KeySource::File(file_path));
+    }
+
+    // Test that init gets the error of a denied keyfile instead of generating a key that replaces it
+    #[cfg(unix)]
+    #[test]
+    fn test_load_existing_key_deny_readable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("key.txt");
+        Key::generate().save_key(&file_path).unwrap();
+        std::fs::set_permissions(&file_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let result = Key::load_existing_key(&None, "CI_SECRET", &file_path, PermissionPolicy::Deny);
+        assert!(result.is_err_and(|err| err.contains("group/others")));
+    }
+
     // Test load_key from file
     #[test]
     fn test_load_key_file() {
@@ -243,9 +585,10 @@ mod tests {
         let key = Key::generate();
         let key_str = key.to_printable();
         let mut file = File::create(&file_path).unwrap();
-        writeln!(file, "{}", key_str).unwrap();
+        writeln!(file, "{}", *key_str).unwrap();
 
-        let (loaded, source) = Key::load_key(&None, &file_path).unwrap();
+        let (loaded, source) =
+            Key::load_key(&None, "CI_SECRET", &file_path, PermissionPolicy::Warn).unwrap();
         assert_eq!(source, KeySource::File(file_path.clone()));
         assert_eq!(loaded.to_printable(), key_str);
     }
@@ -259,7 +602,7 @@ mod tests {
         let key = Key::generate();
         key.save_key(&file_path).unwrap();
         let content = std::fs::read_to_string(&file_path).unwrap();
-        assert_eq!(content.trim(), key.to_printable());
+        assert_eq!(content.trim(), *key.to_printable());
     }
 
     // Test encrypt/decrypt roundtrip
@@ -274,6 +617,199 @@ mod tests {
         assert_eq!(decrypted.content().unwrap(), data);
     }
 
+    // Test that encrypt_base64 writes a versioned header and decrypts again
+    #[test]
+    fn test_encrypt_base64_writes_header() {
+        let key = Key::generate();
+        let pack = EnvironmentPack::File(b"A=1".to_vec());
+
+        let armored = key.encrypt_base64(&pack).unwrap();
+        let header = vault::inspect(&armored).unwrap().unwrap();
+        assert_eq!(
+            header,
+            VaultHeader {
+                flags: 1,
+                key_id: Some(key.key_id()),
+                ..VaultHeader::default()
+            }
+        );
+        assert_eq!(
+            vault::key_fingerprint(&armored).unwrap(),
+            Some(key.fingerprint())
+        );
+
+        let decrypted = key.decrypt_base64(&armored).unwrap();
+        assert_eq!(decrypted.content().unwrap(), b"A=1");
+    }
+
+    // Test that headerless legacy vaults are still readable
+    #[test]
+    fn test_decrypt_base64_legacy() {
+        let key = Key::generate();
+        let pack = EnvironmentPack::File(b"A=1".to_vec());
+
+        let legacy = vault::encode_armor(&key.encrypt(&pack.to_bytes().unwrap()).unwrap());
+        assert!(vault::inspect(&legacy).unwrap().is_none());
+
+        let decrypted = key.decrypt_base64(&legacy).unwrap();
+        assert_eq!(decrypted.content().unwrap(), b"A=1");
+    }
+
+    // Test that a vault from a newer format version reports the version instead of a decryption failure
+    #[test]
+    fn test_decrypt_base64_unknown_version() {
+        let key = Key::generate();
+        let pack = EnvironmentPack::File(b"A=1".to_vec());
+
+        let armored = key.encrypt_base64(&pack).unwrap();
+        let mut bytes = vault::decode_armor(&armored).unwrap();
+        bytes[4] = vault::FORMAT_VERSION + 1;
+
+        let err = key
+            .decrypt_base64(&vault::encode_armor(&bytes))
+            .unwrap_err();
+        assert!(err.contains("not supported"));
+    }
+
+    // Test that tampering with the ciphertext is detected
+    #[test]
+    fn test_decrypt_base64_tampered() {
+        let key = Key::generate();
+        let pack = EnvironmentPack::File(b"A=1".to_vec());
+
+        let armored = key.encrypt_base64(&pack).unwrap();
+        let mut bytes = vault::decode_armor(&armored).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+
+        let err = key
+            .decrypt_base64(&vault::encode_armor(&bytes))
+            .unwrap_err();
+        assert!(err.starts_with("Vault is corrupted"), "{}", err);
+        assert!(err.contains(&key.fingerprint()));
+    }
+
+    // Test that a wrong key is reported with both fingerprints instead of as corruption
+    #[test]
+    fn test_decrypt_base64_wrong_key() {
+        let key = Key::generate();
+        let other = Key::generate();
+        let armored = key
+            .encrypt_base64(&EnvironmentPack::File(b"A=1".to_vec()))
+            .unwrap();
+
+        let err = other.decrypt_base64(&armored).unwrap_err();
+        assert!(err.starts_with("Wrong key"), "{}", err);
+        assert!(err.contains(&key.fingerprint()));
+        assert!(err.contains(&other.fingerprint()));
+
+        let values_vault = values::encrypt(&key, b"A=1\n", None).unwrap();
+        let err = other.decrypt_base64(&values_vault).unwrap_err();
+        assert!(err.starts_with("Wrong key"), "{}", err);
+    }
+
+    // Test loading a passphrase-protected keyfile
+    #[test]
+    fn test_load_protected_key_file() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("protected.key");
+
+        let key = Key::generate();
+        let line = protect::protect(
+            &key,
+            "secret",
+            KdfParams {
+                m_cost: 64,
+                t_cost: 1,
+                p_cost: 1,
+            },
+        )
+        .unwrap();
+        std::fs::write(&file_path, line).unwrap();
+
+        let result = Key::load_key_with(
+            &None,
+            "CI_SECRET",
+            &file_path,
+            PermissionPolicy::Warn,
+            || Ok(Zeroizing::new("secret".to_string())),
+        );
+
+        let (loaded, source) = result.unwrap();
+        assert_eq!(source, KeySource::ProtectedFile(file_path.clone()));
+        assert_eq!(loaded.as_bytes(), key.as_bytes());
+    }
+
+    // Test identity printable roundtrip
+    #[test]
+    fn test_identity_printable_roundtrip() {
+        let identity = Key::generate_identity();
+        let printable = identity.to_printable();
+        assert!(printable.starts_with(IDENTITY_PREFIX));
+
+        let decoded = Key::from_printable(&printable).unwrap();
+        assert_eq!(decoded.kind, KeyKind::Identity);
+        assert_eq!(decoded.as_bytes(), identity.as_bytes());
+        assert_eq!(decoded.recipient(), identity.recipient());
+    }
+
+    // Test that an identity file with its recipient comment can be loaded
+    #[test]
+    fn test_load_identity_file() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("identity.key");
+
+        let identity = Key::generate_identity();
+        identity.save_key(&file_path).unwrap();
+        assert!(std::fs::read_to_string(&file_path)
+            .unwrap()
+            .starts_with("# recipient: "));
+
+        let (loaded, _) =
+            Key::load_key(&None, "CI_SECRET", &file_path, PermissionPolicy::Warn).unwrap();
+        assert_eq!(loaded.kind, KeyKind::Identity);
+        assert_eq!(loaded.as_bytes(), identity.as_bytes());
+    }
+
+    // Test that identities and symmetric keys cannot be mixed up
+    #[test]
+    fn test_decrypt_base64_key_kind_mismatch() {
+        let pack = EnvironmentPack::File(b"A=1".to_vec());
+
+        let symmetric_vault = Key::generate().encrypt_base64(&pack).unwrap();
+        let err = Key::generate_identity()
+            .decrypt_base64(&symmetric_vault)
+            .unwrap_err();
+        assert!(err.contains("symmetric key"));
+
+        let identity = Key::generate_identity();
+        let identity_vault = identity.encrypt_base64(&pack).unwrap();
+        let err = Key::generate().decrypt_base64(&identity_vault).unwrap_err();
+        assert!(err.contains("recipients"));
+        assert_eq!(
+            identity
+                .decrypt_base64(&identity_vault)
+                .unwrap()
+                .content()
+                .unwrap(),
+            b"A=1"
+        );
+    }
+
+    // Test that fingerprints identify a key without revealing it
+    #[test]
+    fn test_fingerprint() {
+        let key = Key::from_bytes(&[7u8; 32]).unwrap();
+        let fingerprint = key.fingerprint();
+        assert_eq!(fingerprint.len(), 2 * KEY_ID_LEN);
+        assert_eq!(
+            fingerprint,
+            Key::from_bytes(&[7u8; 32]).unwrap().fingerprint()
+        );
+        assert_ne!(fingerprint, Key::generate().fingerprint());
+        assert!(!key.to_printable().contains(&fingerprint));
+    }
+
     // Test decrypt_base64 fails on corrupted data
     #[test]
     fn test_decrypt_base64_corrupted() {
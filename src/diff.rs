@@ -0,0 +1,277 @@
+use crate::dotenv::{self, Variable};
+use crate::export;
+use crate::filepacker::{EnvironmentPack, PackEntry};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use zeroize::Zeroizing;
+
+/// How the working environment differs from the vault
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Change {
+    /// Only in the working environment
+    Added,
+    /// Only in the vault
+    Removed,
+    /// In both with different content
+    Changed,
+}
+
+/// A variable or file that differs between the vault and the working environment
+#[derive(Eq, PartialEq, Debug)]
+pub struct Difference {
+    pub change: Change,
+    /// Variable name, or path inside a folder
+    pub name: String,
+    /// Values in the vault and the working environment, only for variables
+    pub vault: Option<Zeroizing<String>>,
+    pub working: Option<Zeroizing<String>>,
+}
+
+/// Compare the decrypted vault with the packed working environment.
+/// Dotenv files are compared by variable, other files by content.
+/// Folders are compared by path, type and content. Modes are ignored since `decrypt` normalizes them.
+pub fn compare(
+    vault: &EnvironmentPack,
+    working: &EnvironmentPack,
+) -> Result<Vec<Difference>, String> {
+    match (vault, working) {
+        (EnvironmentPack::File(vault), EnvironmentPack::File(working)) => {
+            match (variables(vault), variables(working)) {
+                (Some(vault), Some(working)) => Ok(compare_variables(&vault, &working)),
+                _ if vault == working => Ok(Vec::new()),
+                _ => Ok(vec![Difference {
+                    change: Change::Changed,
+                    name: "file content".to_string(),
+                    vault: None,
+                    working: None,
+                }]),
+            }
+        }
+        (EnvironmentPack::Folder(_), EnvironmentPack::Folder(_)) => {
+            Ok(compare_entries(vault.entries()?, working.entries()?))
+        }
+        (EnvironmentPack::File(_), EnvironmentPack::Folder(_)) => {
+            Err("Vault contains a file, but the environment is a folder".to_string())
+        }
+        (EnvironmentPack::Folder(_), EnvironmentPack::File(_)) => {
+            Err("Vault contains a folder, but the environment is a file".to_string())
+        }
+    }
+}
+
+/// Variables of dotenv content with their values as written, `None` if it is not a dotenv file.
+/// Values are not interpolated, so the result does not depend on the process environment.
+fn variables(content: &[u8]) -> Option<Vec<Variable>> {
+    let lines = dotenv::lines(std::str::from_utf8(content).ok()?).ok()?;
+    Some(
+        lines
+            .into_iter()
+            .filter_map(|line| match line {
+                dotenv::Line::Variable { key, raw_value, .. } => Some((
+                    key.to_string(),
+                    Zeroizing::new(raw_value.trim().to_string()),
+                )),
+                dotenv::Line::Other(_) => None,
+            })
+            .collect(),
+    )
+}
+
+fn compare_variables(vault: &[Variable], working: &[Variable]) -> Vec<Difference> {
+    let vault = export::deduplicate(vault);
+    let working = export::deduplicate(working);
+    let value = |variables: &[(&str, &str)], name: &str| {
+        variables
+            .iter()
+            .find(|(existing, _)| *existing == name)
+            .map(|(_, value)| Zeroizing::new(value.to_string()))
+    };
+
+    let mut differences = Vec::new();
+    for (name, old) in &vault {
+        let change = match value(&working, name) {
+            None => Change::Removed,
+            Some(new) if new.as_str() != *old => Change::Changed,
+            Some(_) => continue,
+        };
+        differences.push(Difference {
+            change,
+            name: name.to_string(),
+            vault: Some(Zeroizing::new(old.to_string())),
+            working: value(&working, name),
+        });
+    }
+    for (name, new) in &working {
+        if value(&vault, name).is_none() {
+            differences.push(Difference {
+                change: Change::Added,
+                name: name.to_string(),
+                vault: None,
+                working: Some(Zeroizing::new(new.to_string())),
+            });
+        }
+    }
+    differences
+}
+
+fn compare_entries(vault: Vec<PackEntry>, working: Vec<PackEntry>) -> Vec<Difference> {
+    let by_path = |entries: Vec<PackEntry>| -> BTreeMap<PathBuf, PackEntry> {
+        entries
+            .into_iter()
+            .map(|entry| (entry.path.clone(), entry))
+            .collect()
+    };
+    let vault = by_path(vault);
+    let working = by_path(working);
+    let name = |entry: &PackEntry| {
+        let mut name = entry.path.to_string_lossy().into_owned();
+        if entry.entry_type.is_dir() {
+            name.push('/');
+        }
+        name
+    };
+
+    let mut differences = Vec::new();
+    for (path, old) in &vault {
+        let change = match working.get(path) {
+            None => Change::Removed,
+            Some(new) if new.entry_type != old.entry_type || new.data != old.data => {
+                Change::Changed
+            }
+            Some(_) => continue,
+        };
+        differences.push(Difference {
+            change,
+            name: name(old),
+            vault: None,
+            working: None,
+        });
+    }
+    for (path, new) in &working {
+        if !vault.contains_key(path) {
+            differences.push(Difference {
+                change: Change::Added,
+                name: name(new),
+                vault: None,
+                working: None,
+            });
+        }
+    }
+    differences.sort_by(|a, b| a.name.cmp(&b.name));
+    differences
+}
+
+/// One line per difference: `+` added, `-` removed, `~` changed.
+/// Values of variables are only shown with `show_values`, as written in the file.
+pub fn render(differences: &[Difference], show_values: bool) -> Zeroizing<String> {
+    let mut output = Zeroizing::new(String::new());
+    for difference in differences {
+        let symbol = match difference.change {
+            Change::Added => '+',
+            Change::Removed => '-',
+            Change::Changed => '~',
+        };
+        let line = match (&difference.vault, &difference.working) {
+            (vault, working) if show_values && (vault.is_some() || working.is_some()) => {
+                let mut line = Zeroizing::new(format!("{} {}=", symbol, difference.name));
+                for (index, value) in [vault, working].into_iter().flatten().enumerate() {
+                    if index > 0 {
+                        line.push_str(" -> ");
+                    }
+                    line.push_str(value);
+                }
+                line
+            }
+            _ => Zeroizing::new(format!("{} {}", symbol, difference.name)),
+        };
+        output.push_str(&line);
+        output.push('\n');
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filepacker::PackOptions;
+    use std::fs;
+    use tempfile::tempdir;
+
+    // Test that dotenv files are compared by variable and values are only shown on request
+    #[test]
+    fn test_variables() {
+        let vault = EnvironmentPack::File(b"A=1\nB=secret\nC=3\n".to_vec());
+        let working = EnvironmentPack::File(b"# edited\nA=1\nC=changed\nD=new\nC=4\n".to_vec());
+        let differences = compare(&vault, &working).unwrap();
+        let changes: Vec<(Change, &str)> = differences
+            .iter()
+            .map(|d| (d.change, d.name.as_str()))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (Change::Removed, "B"),
+                (Change::Changed, "C"),
+                (Change::Added, "D")
+            ]
+        );
+
+        assert_eq!(*render(&differences, false), "- B\n~ C\n+ D\n");
+        assert_eq!(
+            *render(&differences, true),
+            "- B=secret\n~ C=3 -> 4\n+ D=new\n"
+        );
+
+        let same = EnvironmentPack::File(b"C=3\nB=secret\nA=1\n".to_vec());
+        assert!(compare(&vault, &same).unwrap().is_empty());
+    }
+
+    // Test that values are compared as written, not after interpolation from the process environment
+    #[test]
+    fn test_raw_values() {
+        let vault = EnvironmentPack::File(b"A=${ENVBUDDEL_UNSET_X}\nB='x y'\n".to_vec());
+        let working = EnvironmentPack::File(b"A=${ENVBUDDEL_UNSET_Y}\nB='x y'\n".to_vec());
+        let differences = compare(&vault, &working).unwrap();
+        assert_eq!(
+            *render(&differences, true),
+            "~ A=${ENVBUDDEL_UNSET_X} -> ${ENVBUDDEL_UNSET_Y}\n"
+        );
+    }
+
+    // Test that files which are not dotenv are compared by content
+    #[test]
+    fn test_opaque_file() {
+        let vault = EnvironmentPack::File(b"not dotenv".to_vec());
+        let working = EnvironmentPack::File(b"not dotenv either".to_vec());
+        assert_eq!(compare(&vault, &working).unwrap().len(), 1);
+        assert!(
+            compare(&vault, &EnvironmentPack::File(b"not dotenv".to_vec()))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    // Test that folders report added, removed and modified files but ignore modes
+    #[test]
+    fn test_folders() {
+        let dir = tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/changed"), "old").unwrap();
+        fs::write(dir.path().join("removed"), "x").unwrap();
+        fs::write(dir.path().join("same"), "x").unwrap();
+        let vault = EnvironmentPack::from_path(dir.path(), &PackOptions::default()).unwrap();
+
+        fs::write(dir.path().join("sub/changed"), "new").unwrap();
+        fs::remove_file(dir.path().join("removed")).unwrap();
+        fs::create_dir(dir.path().join("added")).unwrap();
+        crate::fsutil::set_mode(&dir.path().join("same"), 0o600).unwrap();
+        let working = EnvironmentPack::from_path(dir.path(), &PackOptions::default()).unwrap();
+
+        let differences = compare(&vault, &working).unwrap();
+        assert_eq!(
+            *render(&differences, true),
+            "+ added/\n- removed\n~ sub/changed\n"
+        );
+        assert!(compare(&vault, &EnvironmentPack::File(Vec::new())).is_err());
+    }
+}
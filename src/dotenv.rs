@@ -0,0 +1,475 @@
+use std::env;
+use std::iter::Peekable;
+use std::str::Chars;
+use zeroize::Zeroizing;
+
+/// Name and value of a variable. The value is wiped from memory when dropped.
+pub type Variable = (String, Zeroizing<String>);
+
+/// A line of a dotenv file as written
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum Line<'a> {
+    /// Blank line or comment, kept verbatim
+    Other(&'a str),
+    /// Variable assignment. `prefix` is the text up to and including `=`,
+    /// `raw_value` the unparsed text after it.
+    Variable {
+        key: &'a str,
+        prefix: &'a str,
+        raw_value: &'a str,
+    },
+}
+
+/// Split dotenv content into its lines without interpreting the values.
+/// A quoted value spanning several lines is a single `Line::Variable`.
+pub fn lines(content: &str) -> Result<Vec<Line<'_>>, String> {
+    let spans = spans(content);
+    let mut lines = Vec::new();
+
+    let mut index = 0;
+    while index < spans.len() {
+        let number = index + 1;
+        let (start, end) = spans[index];
+        let line = &content[start..end];
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            lines.push(Line::Other(line));
+            index += 1;
+            continue;
+        }
+
+        let separator = line
+            .find('=')
+            .ok_or_else(|| format!("Line {}: expected KEY=value", number))?;
+
+        let name = line[..separator].trim();
+        let key = match name.strip_prefix("export") {
+            Some(rest) if rest.starts_with(char::is_whitespace) => rest.trim_start(),
+            _ => name,
+        };
+        if !is_valid_key(key) {
+            return Err(format!("Line {}: invalid variable name {:?}", number, key));
+        }
+
+        // Extend the value over the following lines until its quotes are closed
+        let value_start = start + separator + 1;
+        let mut value_end = end;
+        while evaluate(&content[value_start..value_end], &|_| None)
+            .map_err(|e| format!("Line {}: {}", number, e))?
+            .is_none()
+        {
+            index += 1;
+            if index == spans.len() {
+                return Err(format!("Line {}: unterminated quoted value", number));
+            }
+            value_end = spans[index].1;
+        }
+
+        lines.push(Line::Variable {
+            key,
+            prefix: &content[start..value_start],
+            raw_value: &content[value_start..value_end],
+        });
+        index += 1;
+    }
+
+    Ok(lines)
+}
+
+/// Byte ranges of the lines of `content` without their line break
+fn spans(content: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut start = 0;
+    for line in content.split_inclusive('\n') {
+        let text = line.strip_suffix('\n').unwrap_or(line);
+        let text = text.strip_suffix('\r').unwrap_or(text);
+        spans.push((start, start + text.len()));
+        start += line.len();
+    }
+    spans
+}
+
+/// Parse dotenv content into its variables in order of appearance.
+///
+/// Supports comments, `export` prefixes, inline comments after unquoted values and
+/// values wrapped in single or double quotes, which may span multiple lines.
+/// Single quoted values are taken literally. Double quoted values may contain the escapes
+/// `\\n`, `\\r`, `\\t`, `\\"`, `\\$` and `\\\\`.
+/// Unquoted and double quoted values expand `$NAME`, `${NAME}`, `${NAME:-default}` and `${NAME-default}`
+/// from the variables defined above, falling back to the process environment.
+pub fn parse(content: &str) -> Result<Vec<Variable>, String> {
+    let mut variables: Vec<Variable> = Vec::new();
+    let mut number = 1;
+    for line in lines(content)? {
+        match line {
+            Line::Other(_) => number += 1,
+            Line::Variable { key, raw_value, .. } => {
+                let resolve = |name: &str| {
+                    variables
+                        .iter()
+                        .rev()
+                        .find(|(key, _)| key == name)
+                        .map(|(_, value)| value.clone())
+                        .or_else(|| env::var(name).ok().map(Zeroizing::new))
+                };
+                let value = evaluate(raw_value, &resolve)
+                    .map_err(|e| format!("Line {}: {}", number, e))?
+                    .ok_or_else(|| format!("Line {}: unterminated quoted value", number))?;
+                number += 1 + raw_value.matches('\n').count();
+                variables.push((key.to_string(), value));
+            }
+        }
+    }
+    Ok(variables)
+}
+
+/// Set a variable in dotenv content, keeping all other lines as they are.
+/// The last definition of the variable is replaced, otherwise it is appended.
+pub fn set(content: &str, key: &str, value: &str) -> Result<Zeroizing<String>, String> {
+    if !is_valid_key(key) {
+        return Err(format!("Invalid variable name {:?}", key));
+    }
+    let value = quote(value);
+
+    let lines = self::lines(content)?;
+    let position = lines
+        .iter()
+        .rposition(|line| matches!(line, Line::Variable { key: name, .. } if *name == key));
+
+    let mut updated = Zeroizing::new(String::with_capacity(content.len() + value.len() + 2));
+    for (index, line) in lines.iter().enumerate() {
+        match line {
+            Line::Other(text) => updated.push_str(text),
+            Line::Variable {
+                prefix, raw_value, ..
+            } => {
+                updated.push_str(prefix);
+                updated.push_str(if position == Some(index) {
+                    &value
+                } else {
+                    raw_value
+                });
+            }
+        }
+        updated.push('\n');
+    }
+    if position.is_none() {
+        updated.push_str(key);
+        updated.push('=');
+        updated.push_str(&value);
+        updated.push('\n');
+    }
+    Ok(updated)
+}
+
+/// Quote a value so `parse` reads it back unchanged
+pub fn quote(value: &str) -> Zeroizing<String> {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-./:@%+,".contains(c);
+    if value.chars().all(plain) {
+        Zeroizing::new(value.to_string())
+    } else if !value.contains(['\'', '\n', '\r']) {
+        Zeroizing::new(format!("'{}'", value))
+    } else {
+        let mut quoted = Zeroizing::new(String::with_capacity(value.len() * 2 + 2));
+        quoted.push('"');
+        for c in value.chars() {
+            match c {
+                '\n' => quoted.push_str("\\n"),
+                '\r' => quoted.push_str("\\r"),
+                '\t' => quoted.push_str("\\t"),
+                '"' => quoted.push_str("\\\""),
+                '$' => quoted.push_str("\\$"),
+                '\\' => quoted.push_str("\\\\"),
+                c => quoted.push(c),
+            }
+        }
+        quoted.push('"');
+        quoted
+    }
+}
+
+fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+/// Evaluate the raw text after `=`. Returns `None` if a quote is not closed yet.
+fn evaluate(
+    raw_value: &str,
+    resolve: &dyn Fn(&str) -> Option<Zeroizing<String>>,
+) -> Result<Option<Zeroizing<String>>, String> {
+    let value = raw_value.trim_start();
+    let mut chars = value.chars().peekable();
+    let mut result = Zeroizing::new(String::with_capacity(value.len()));
+
+    match chars.peek() {
+        Some(&quote) if quote == '\'' || quote == '"' => {
+            chars.next();
+            loop {
+                match chars.next() {
+                    None => return Ok(None),
+                    Some(c) if c == quote => break,
+                    Some('\\') if quote == '"' => match chars.next() {
+                        None => return Ok(None),
+                        Some('n') => result.push('\n'),
+                        Some('r') => result.push('\r'),
+                        Some('t') => result.push('\t'),
+                        Some(c @ ('"' | '\\' | '$')) => result.push(c),
+                        Some(other) => {
+                            result.push('\\');
+                            result.push(other);
+                        }
+                    },
+                    Some('$') if quote == '"' => interpolate(&mut chars, resolve, &mut result)?,
+                    Some(c) => result.push(c),
+                }
+            }
+            let rest: String = chars.collect();
+            let rest = rest.trim_start();
+            if !rest.is_empty() && !rest.starts_with('#') {
+                return Err(format!("unexpected {:?} after the closing quote", rest));
+            }
+        }
+        _ => {
+            // An unquoted value ends at a `#` preceded by whitespace, so `A=#1` keeps its value
+            let mut previous = if value.len() < raw_value.len() {
+                ' '
+            } else {
+                '='
+            };
+            while let Some(c) = chars.next() {
+                if c == '#' && previous.is_whitespace() {
+                    break;
+                }
+                if c == '$' {
+                    interpolate(&mut chars, resolve, &mut result)?;
+                } else {
+                    result.push(c);
+                }
+                previous = c;
+            }
+            let len = result.trim_end().len();
+            result.truncate(len);
+        }
+    }
+    Ok(Some(result))
+}
+
+/// Expand the variable reference following a `$`. A `$` not followed by a name is kept.
+fn interpolate(
+    chars: &mut Peekable<Chars>,
+    resolve: &dyn Fn(&str) -> Option<Zeroizing<String>>,
+    result: &mut String,
+) -> Result<(), String> {
+    if chars.next_if_eq(&'{').is_none() {
+        let mut name = String::new();
+        while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            name.push(c);
+        }
+        if is_valid_key(&name) {
+            result.push_str(&resolve(&name).unwrap_or_default());
+        } else {
+            result.push('$');
+            result.push_str(&name);
+        }
+        return Ok(());
+    }
+
+    let mut reference = String::new();
+    loop {
+        match chars.next() {
+            Some('}') => break,
+            Some(c) => reference.push(c),
+            None => return Err(format!("unterminated variable reference ${{{}", reference)),
+        }
+    }
+
+    let end = reference
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .unwrap_or(reference.len());
+    let (name, operator) = reference.split_at(end);
+    if !is_valid_key(name) {
+        return Err(format!("invalid variable reference ${{{}}}", reference));
+    }
+    let value = resolve(name);
+    let expanded = if let Some(default) = operator.strip_prefix(":-") {
+        value
+            .filter(|value| !value.is_empty())
+            .unwrap_or_else(|| Zeroizing::new(default.to_string()))
+    } else if let Some(default) = operator.strip_prefix('-') {
+        value.unwrap_or_else(|| Zeroizing::new(default.to_string()))
+    } else if operator.is_empty() {
+        value.unwrap_or_default()
+    } else {
+        return Err(format!("invalid variable reference ${{{}}}", reference));
+    };
+    result.push_str(&expanded);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test parsing of plain, quoted and commented lines
+    #[test]
+    fn test_parse_simple() {
+        let content = "# comment\nA=1\n\nB = \"two words\"\nC='single'\nD=\n";
+        let variables = parse(content).unwrap();
+        assert_eq!(
+            variables,
+            vec![
+                ("A".to_string(), Zeroizing::new("1".to_string())),
+                ("B".to_string(), Zeroizing::new("two words".to_string())),
+                ("C".to_string(), Zeroizing::new("single".to_string())),
+                ("D".to_string(), Zeroizing::new("".to_string())),
+            ]
+        );
+    }
+
+    // Test that the first '=' separates key and value
+    #[test]
+    fn test_parse_value_with_equals() {
+        let variables = parse("URL=postgres://u:p@host/db?x=y").unwrap();
+        assert_eq!(*variables[0].1, "postgres://u:p@host/db?x=y");
+    }
+
+    // Test that lines keep their raw text
+    #[test]
+    fn test_lines_raw() {
+        let lines = lines("# c\nA = 'x' \n").unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                Line::Other("# c"),
+                Line::Variable {
+                    key: "A",
+                    prefix: "A =",
+                    raw_value: " 'x' ",
+                },
+            ]
+        );
+    }
+
+    // Test that set replaces the last definition and keeps everything else
+    #[test]
+    fn test_set_replaces() {
+        let content = "# c\nA=1\nB = 2\nB=3\n";
+        let updated = set(content, "B", "new value").unwrap();
+        assert_eq!(*updated, "# c\nA=1\nB = 2\nB='new value'\n");
+        assert_eq!(*parse(&updated).unwrap()[2].1, "new value");
+    }
+
+    // Test that set appends unknown variables
+    #[test]
+    fn test_set_appends() {
+        assert_eq!(*set("A=1", "C", "x").unwrap(), "A=1\nC=x\n");
+        assert!(set("A=1", "1C", "x").is_err());
+    }
+
+    // Test that quoted values are read back unchanged
+    #[test]
+    fn test_quote_roundtrip() {
+        for value in [
+            "plain",
+            "",
+            "with space",
+            "it's",
+            "#hash",
+            "a\"b",
+            "multi\nline",
+            "both ' and \" \\n",
+            "$HOME ${HOME}",
+            "it's $HOME",
+            "a # b",
+        ] {
+            let content = format!("A={}", *quote(value));
+            assert_eq!(*parse(&content).unwrap()[0].1, value);
+        }
+    }
+
+    // Test export prefixes and inline comments
+    #[test]
+    fn test_parse_export_and_comments() {
+        let content = "export A=1 # one\nexport  B='x # y' # two\nC=a#b\nD= # empty\nexport=3\n";
+        let variables = parse(content).unwrap();
+        assert_eq!(
+            variables,
+            vec![
+                ("A".to_string(), Zeroizing::new("1".to_string())),
+                ("B".to_string(), Zeroizing::new("x # y".to_string())),
+                ("C".to_string(), Zeroizing::new("a#b".to_string())),
+                ("D".to_string(), Zeroizing::new("".to_string())),
+                ("export".to_string(), Zeroizing::new("3".to_string())),
+            ]
+        );
+    }
+
+    // Test that quoted values can span multiple lines
+    #[test]
+    fn test_parse_multiline() {
+        let content = "A=\"first\nsecond\"\nB='x\n\ny'\nC=3\n";
+        let lines = lines(content).unwrap();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            Line::Variable {
+                key: "A",
+                prefix: "A=",
+                raw_value: "\"first\nsecond\"",
+            }
+        );
+        let variables = parse(content).unwrap();
+        assert_eq!(*variables[0].1, "first\nsecond");
+        assert_eq!(*variables[1].1, "x\n\ny");
+        assert_eq!(*variables[2].1, "3");
+    }
+
+    // Test escapes in double quotes and literal single quotes
+    #[test]
+    fn test_parse_escapes() {
+        let content = r#"A="tab\tquote\"dollar\$HOME\\\x"
+B='no\nescape $HOME'"#;
+        let variables = parse(content).unwrap();
+        assert_eq!(*variables[0].1, "tab\tquote\"dollar$HOME\\\\x");
+        assert_eq!(*variables[1].1, "no\\nescape $HOME");
+    }
+
+    // Test interpolation of earlier variables, defaults and the process environment
+    #[test]
+    fn test_parse_interpolation() {
+        let content = "\
+            HOST=db\n\
+            EMPTY=\n\
+            URL=\"postgres://${HOST}:${ENVBUDDEL_TEST_UNSET:-5432}/$HOST\"\n\
+            A=${EMPTY:-fallback} ${EMPTY-kept}|${ENVBUDDEL_TEST_UNSET}|\n\
+            COST=$5 and $\n\
+            P=${PATH}\n";
+        let variables = parse(content).unwrap();
+        assert_eq!(*variables[2].1, "postgres://db:5432/db");
+        assert_eq!(*variables[3].1, "fallback ||");
+        assert_eq!(*variables[4].1, "$5 and $");
+        assert_eq!(*variables[5].1, env::var("PATH").unwrap_or_default());
+    }
+
+    // Test that malformed lines report their line number
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("A=1\nnot a variable").unwrap_err().contains("Line 2"));
+        assert!(parse("1A=1").unwrap_err().contains("Line 1"));
+        assert!(parse("A='a\nb'\nB=\"open\n")
+            .unwrap_err()
+            .contains("Line 3: unterminated"));
+        assert!(parse("A='a\nb'\nB='x' y").unwrap_err().contains("Line 3"));
+        assert!(parse("A=${B").unwrap_err().contains("Line 1"));
+        assert!(parse("\nA=${B:x}").unwrap_err().contains("Line 2"));
+        assert!(parse("A=\"multi\nline\"\nB=${1}")
+            .unwrap_err()
+            .contains("Line 3"));
+    }
+}
@@ -0,0 +1,152 @@
+use crate::filepacker::{EnvironmentPack, PackOptions};
+use crate::fsutil;
+use log::{debug, warn};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use tempfile::TempDir;
+
+/// Directory for decrypted temp files. Prefers a tmpfs so plaintext never hits the disk.
+fn private_tmp() -> PathBuf {
+    let shm = Path::new("/dev/shm");
+    if shm.is_dir() {
+        shm.to_path_buf()
+    } else {
+        env::temp_dir()
+    }
+}
+
+/// Editor command from $VISUAL or $EDITOR, falling back to vi
+fn editor() -> Vec<String> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    editor.split_whitespace().map(str::to_string).collect()
+}
+
+/// Run the editor on `path` and wait for it to exit
+fn run_editor(path: &Path) -> Result<(), String> {
+    let editor = editor();
+    let (program, args) = editor
+        .split_first()
+        .ok_or("The editor set in $VISUAL/$EDITOR is empty")?;
+
+    debug!("Running {:?} on {:?}", editor, path);
+    let status = process::Command::new(program)
+        .args(args)
+        .arg(path)
+        .status()
+        .map_err(|e| format!("Failed to start editor {:?}: {}", program, e))?;
+    if !status.success() {
+        return Err(format!("Editor {:?} exited with {}", program, status));
+    }
+    Ok(())
+}
+
+/// Ask whether the editor should be opened again, defaults to yes
+pub fn ask_retry() -> Result<bool, String> {
+    print!("Re-open the editor to fix it? [Y/n] ");
+    io::stdout()
+        .flush()
+        .map_err(|e| format!("Failed to write prompt: {}", e))?;
+    let mut answer = String::new();
+    let read = io::stdin()
+        .read_line(&mut answer)
+        .map_err(|e| format!("Failed to read answer: {}", e))?;
+    Ok(read > 0 && !answer.trim().eq_ignore_ascii_case("n"))
+}
+
+/// Private workspace holding decrypted content while it is edited.
+/// Its content is overwritten before it is removed.
+pub struct Workspace {
+    dir: TempDir,
+    path: PathBuf,
+    options: PackOptions,
+}
+
+impl Workspace {
+    /// Decrypt the pack into a private temp dir. `name` is the file or folder name shown in the editor.
+    pub fn new(pack: &EnvironmentPack, name: &str, options: &PackOptions) -> Result<Self, String> {
+        // The temp dir is 0700, the environment is unpacked inside so it cannot widen the permissions
+        let mut builder = tempfile::Builder::new();
+        builder.prefix("envbuddel-");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            builder.permissions(fs::Permissions::from_mode(0o700));
+        }
+        let dir = builder
+            .tempdir_in(private_tmp())
+            .map_err(|e| format!("Failed to create private temp dir: {}", e))?;
+        let path = dir.path().join(name);
+        if let EnvironmentPack::Folder(_) = pack {
+            fs::create_dir(&path).map_err(|e| format!("Failed to create {:?}: {}", path, e))?;
+        }
+        pack.unpack(&path, options)?;
+        Ok(Self {
+            dir,
+            path,
+            options: options.clone(),
+        })
+    }
+
+    /// Open the editor and return the pack if its content changed
+    pub fn edit(&self, pack: &EnvironmentPack) -> Result<Option<EnvironmentPack>, String> {
+        self.edit_with(pack, run_editor)
+    }
+
+    /// Like `edit`, with `editor` run on the decrypted file or folder
+    fn edit_with(
+        &self,
+        pack: &EnvironmentPack,
+        editor: impl FnOnce(&Path) -> Result<(), String>,
+    ) -> Result<Option<EnvironmentPack>, String> {
+        editor(&self.path)?;
+        let edited = EnvironmentPack::from_path(&self.path, &self.options)?;
+        if edited.same_content(pack)? {
+            Ok(None)
+        } else {
+            Ok(Some(edited))
+        }
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        if let Err(err) = fsutil::shred(self.dir.path()) {
+            warn!(
+                "Failed to overwrite temp files in {:?}: {}",
+                self.dir.path(),
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    // Test that closing the editor without changes is no change, although unpack normalized the modes
+    #[test]
+    fn test_edit_unchanged_folder() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("pub"), "public").unwrap();
+        fsutil::set_mode(&dir.path().join("pub"), 0o644).unwrap();
+        let options = PackOptions::default();
+        let pack = EnvironmentPack::from_path(dir.path(), &options).unwrap();
+
+        let workspace = Workspace::new(&pack, "env", &options).unwrap();
+        assert!(workspace.edit_with(&pack, |_| Ok(())).unwrap().is_none());
+
+        let edited = workspace
+            .edit_with(&pack, |path| {
+                fs::write(path.join("pub"), "changed").map_err(|e| e.to_string())
+            })
+            .unwrap();
+        assert!(edited.is_some());
+    }
+}
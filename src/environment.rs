@@ -0,0 +1,181 @@
+use crate::filepacker::PackOptions;
+use crate::fsutil::PermissionPolicy;
+use crate::vault::VaultMode;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Environment variable holding the key of the default environment
+pub const KEY_VAR: &str = "CI_SECRET";
+
+const KEYFILE: &str = "vault.key";
+const VAULT: &str = "vault.enc";
+const ENV_CONF: &str = ".env";
+
+/// Key source, vault and environment path of one environment of the project, e.g. `prod`
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Environment {
+    /// Name of the environment, `None` for the default environment
+    pub name: Option<String>,
+    pub keyfile: PathBuf,
+    /// Environment variable the key is read from
+    pub key_var: String,
+    /// Content of the key, taken from --key or `key_var`
+    pub key: Option<String>,
+    /// Whether `key` was passed with --key
+    pub key_from_arg: bool,
+    pub env_conf: PathBuf,
+    pub vault: PathBuf,
+    /// Vault mode used when no vault exists yet
+    pub mode: Option<VaultMode>,
+    /// What to do when the keyfile or plaintext is readable by other users
+    pub permissions: PermissionPolicy,
+    /// Modes and times of folder environments
+    pub pack: PackOptions,
+}
+
+impl Environment {
+    /// Conventional paths of the named environment: `vault.<name>.key`, `vault.<name>.enc`, `.env.<name>`
+    /// and the key in `CI_SECRET_<NAME>`. Without a name the default environment is used.
+    pub fn named(name: Option<&str>) -> Result<Self, String> {
+        let Some(name) = name else {
+            return Ok(Self {
+                name: None,
+                keyfile: PathBuf::from(KEYFILE),
+                key_var: KEY_VAR.to_string(),
+                key: None,
+                key_from_arg: false,
+                env_conf: PathBuf::from(ENV_CONF),
+                vault: PathBuf::from(VAULT),
+                mode: None,
+                permissions: PermissionPolicy::default(),
+                pack: PackOptions::default(),
+            });
+        };
+
+        if !is_valid_name(name) {
+            return Err(format!(
+                "Invalid environment name {:?}. Use letters, digits, '-' and '_'",
+                name
+            ));
+        }
+        Ok(Self {
+            name: Some(name.to_string()),
+            keyfile: PathBuf::from(format!("vault.{}.key", name)),
+            key_var: format!("{}_{}", KEY_VAR, name.to_uppercase().replace('-', "_")),
+            key: None,
+            key_from_arg: false,
+            env_conf: PathBuf::from(format!("{}.{}", ENV_CONF, name)),
+            vault: PathBuf::from(format!("vault.{}.enc", name)),
+            mode: None,
+            permissions: PermissionPolicy::default(),
+            pack: PackOptions::default(),
+        })
+    }
+
+    /// Name shown to the user
+    pub fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or("default")
+    }
+
+    /// Where `key` was read from, for messages
+    pub fn key_name(&self) -> &str {
+        if self.key_from_arg {
+            "--key"
+        } else {
+            &self.key_var
+        }
+    }
+
+    /// Key from the environment variable of this environment
+    pub fn key_from_var(&self) -> Option<String> {
+        env::var(&self.key_var).ok().filter(|key| !key.is_empty())
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Names of the environments with a vault or keyfile in `dir`, the default environment (`None`) first
+pub fn discover(dir: &Path) -> Result<Vec<Option<String>>, String> {
+    let mut names = Vec::new();
+    let entries = fs::read_dir(dir).map_err(|e| format!("Failed to read dir {:?}: {}", dir, e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Failed to read dir {:?}: {}", dir, e))?;
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+        let name = match file_name {
+            KEYFILE | VAULT => None,
+            _ => match file_name
+                .strip_prefix("vault.")
+                .and_then(|rest| rest.strip_suffix(".enc").or(rest.strip_suffix(".key")))
+            {
+                Some(name) if is_valid_name(name) => Some(name.to_string()),
+                _ => continue,
+            },
+        };
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    // Test the conventional paths of a named environment
+    #[test]
+    fn test_named() {
+        let environment = Environment::named(Some("prod-eu")).unwrap();
+        assert_eq!(environment.keyfile, PathBuf::from("vault.prod-eu.key"));
+        assert_eq!(environment.vault, PathBuf::from("vault.prod-eu.enc"));
+        assert_eq!(environment.env_conf, PathBuf::from(".env.prod-eu"));
+        assert_eq!(environment.key_var, "CI_SECRET_PROD_EU");
+        assert_eq!(environment.label(), "prod-eu");
+
+        let default = Environment::named(None).unwrap();
+        assert_eq!(default.keyfile, PathBuf::from("vault.key"));
+        assert_eq!(default.key_var, "CI_SECRET");
+        assert_eq!(default.label(), "default");
+    }
+
+    // Test that names cannot escape the project directory
+    #[test]
+    fn test_invalid_name() {
+        for name in ["", "../prod", "a/b", "prod env"] {
+            assert!(Environment::named(Some(name)).is_err());
+        }
+    }
+
+    // Test that environments are found by their vaults and keyfiles
+    #[test]
+    fn test_discover() {
+        let dir = tempdir().unwrap();
+        for file in [
+            "vault.key",
+            "vault.prod.enc",
+            "vault.prod.key",
+            "vault.dev.key",
+            ".env",
+        ] {
+            fs::write(dir.path().join(file), "").unwrap();
+        }
+        fs::write(dir.path().join("vault.a.b.enc"), "").unwrap();
+
+        let names = discover(dir.path()).unwrap();
+        assert_eq!(
+            names,
+            vec![None, Some("dev".to_string()), Some("prod".to_string())]
+        );
+    }
+}
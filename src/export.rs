@@ -0,0 +1,497 @@
+use crate::dotenv::{self, Variable};
+use clap::ValueEnum;
+use rand::RngCore;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/// Maximum size of a GitLab dotenv report artifact
+const GITLAB_MAX_SIZE: usize = 5 * 1024;
+
+/// Default number of variables GitLab accepts from a dotenv report
+const GITLAB_MAX_VARIABLES: usize = 20;
+
+/// Minimum length of a value GitLab can mask
+const GITLAB_MASK_MIN_LEN: usize = 8;
+
+/// Environment variable naming the file GitHub Actions reads variables for later steps from
+pub const GITHUB_ENV: &str = "GITHUB_ENV";
+
+/// Output formats of the `export` command
+#[derive(Eq, PartialEq, Debug, Clone, Copy, ValueEnum)]
+pub enum Format {
+    /// `export NAME='value'` for bash, zsh and other POSIX shells
+    Bash,
+    /// `set -gx NAME 'value'` for fish
+    Fish,
+    /// `$env:NAME = 'value'` for PowerShell
+    Powershell,
+    /// A single JSON object
+    Json,
+    /// Dotenv file that envbuddel reads back unchanged
+    Dotenv,
+    /// `NAME=value` for `docker run --env-file`. Multi-line values are not supported by Docker.
+    Docker,
+    /// `::add-mask::` commands for GitHub Actions. The variables are appended to `$GITHUB_ENV`.
+    Github,
+    /// GitLab `artifacts:reports:dotenv` file. Multi-line values are rejected.
+    Gitlab,
+}
+
+/// Render the variables to `out`. For GitHub the variables are appended to the `github_env` file first,
+/// so nothing is printed when it is missing or cannot be written.
+pub fn write(
+    variables: &[Variable],
+    format: Format,
+    github_env: Option<&Path>,
+    out: &mut impl Write,
+) -> Result<(), String> {
+    let output = render(variables, format)?;
+    if format == Format::Github {
+        let path = github_env
+            .ok_or_else(|| format!("{} is not set, run this inside GitHub Actions", GITHUB_ENV))?;
+        append_github_env(variables, path)?;
+    }
+    out.write_all(output.as_bytes())
+        .map_err(|e| format!("Failed to write output: {}", e))
+}
+
+/// Render the variables in the given format.
+/// A variable defined multiple times is rendered once with its last value.
+pub fn render(variables: &[Variable], format: Format) -> Result<Zeroizing<String>, String> {
+    let variables = deduplicate(variables);
+    if format == Format::Json {
+        return Ok(json(&variables));
+    }
+
+    let mut output = Zeroizing::new(String::new());
+    for (name, value) in variables {
+        let line = match format {
+            Format::Bash => Zeroizing::new(format!("export {}={}", name, *posix_quote(value))),
+            Format::Fish => Zeroizing::new(format!("set -gx {} {}", name, *fish_quote(value))),
+            Format::Powershell => {
+                Zeroizing::new(format!("$env:{} = {}", name, *powershell_quote(value)))
+            }
+            Format::Dotenv => Zeroizing::new(format!("{}={}", name, *dotenv::quote(value))),
+            Format::Docker => {
+                if value.contains(['\n', '\r']) {
+                    return Err(format!(
+                        "Variable {} has a multi-line value, which Docker env files cannot represent",
+                        name
+                    ));
+                }
+                Zeroizing::new(format!("{}={}", name, value))
+            }
+            Format::Github => github_masks(value),
+            Format::Gitlab => {
+                if value.contains(['\n', '\r']) {
+                    return Err(format!(
+                        "Variable {} has a multi-line value, which GitLab dotenv reports do not support",
+                        name
+                    ));
+                }
+                Zeroizing::new(format!("{}={}", name, value))
+            }
+            Format::Json => unreachable!(),
+        };
+        if !line.is_empty() {
+            output.push_str(&line);
+            output.push('\n');
+        }
+    }
+    if format == Format::Gitlab && output.len() > GITLAB_MAX_SIZE {
+        return Err(format!(
+            "GitLab dotenv reports are limited to {} bytes, the variables need {}",
+            GITLAB_MAX_SIZE,
+            output.len()
+        ));
+    }
+    Ok(output)
+}
+
+/// Problems GitLab does not reject but that likely break the pipeline or leak a secret
+pub fn gitlab_warnings(variables: &[Variable]) -> Vec<String> {
+    let variables = deduplicate(variables);
+    let mut warnings = Vec::new();
+    if variables.len() > GITLAB_MAX_VARIABLES {
+        warnings.push(format!(
+            "GitLab accepts only {} variables from a dotenv report by default, found {}",
+            GITLAB_MAX_VARIABLES,
+            variables.len()
+        ));
+    }
+    for (name, value) in variables {
+        if value.trim() != value {
+            warnings.push(format!(
+                "Variable {} has surrounding whitespace, GitLab trims it",
+                name
+            ));
+        }
+        if let Err(reason) = gitlab_maskable(value) {
+            warnings.push(format!(
+                "Variable {} cannot be masked by GitLab: {}",
+                name, reason
+            ));
+        }
+    }
+    warnings
+}
+
+/// GitLab masks values of at least 8 characters from the Base64 alphabet plus `@:.~-_`
+fn gitlab_maskable(value: &str) -> Result<(), String> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || "+/=@:.~-_".contains(c);
+    if value.chars().count() < GITLAB_MASK_MIN_LEN {
+        return Err(format!(
+            "it is shorter than {} characters",
+            GITLAB_MASK_MIN_LEN
+        ));
+    }
+    if let Some(c) = value.chars().find(|c| !allowed(*c)) {
+        return Err(format!("it contains {:?}", c));
+    }
+    Ok(())
+}
+
+/// Mask commands for the value. GitHub masks line by line, so every line is registered on its own.
+fn github_masks(value: &str) -> Zeroizing<String> {
+    let mut masks: Vec<Zeroizing<String>> = value
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| Zeroizing::new(format!("::add-mask::{}", *github_escape(line))))
+        .collect();
+    masks.dedup();
+
+    let mut output = Zeroizing::new(String::new());
+    for mask in masks {
+        if !output.is_empty() {
+            output.push('\n');
+        }
+        output.push_str(&mask);
+    }
+    output
+}
+
+/// Escape data of a workflow command
+fn github_escape(value: &str) -> Zeroizing<String> {
+    let mut escaped = Zeroizing::new(String::with_capacity(value.len()));
+    for c in value.chars() {
+        match c {
+            '%' => escaped.push_str("%25"),
+            '\r' => escaped.push_str("%0D"),
+            '\n' => escaped.push_str("%0A"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Content for $GITHUB_ENV. Multi-line values use the heredoc syntax with a random delimiter.
+pub fn github_env(variables: &[Variable]) -> Zeroizing<String> {
+    let mut output = Zeroizing::new(String::new());
+    for (name, value) in deduplicate(variables) {
+        if !value.contains(['\n', '\r']) {
+            output.push_str(&Zeroizing::new(format!("{}={}\n", name, value)));
+            continue;
+        }
+        // The delimiter must not appear in the value or it could end the value early and inject variables
+        let delimiter = loop {
+            let mut bytes = [0u8; 16];
+            rand::rng().fill_bytes(&mut bytes);
+            let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            let delimiter = format!("ghadelimiter_{}", hex);
+            if !value.contains(&delimiter) {
+                break delimiter;
+            }
+        };
+        output.push_str(&Zeroizing::new(format!(
+            "{}<<{}\n{}\n{}\n",
+            name, delimiter, value, delimiter
+        )));
+    }
+    output
+}
+
+/// Append the variables to the $GITHUB_ENV file so later steps of the job see them
+pub fn append_github_env(variables: &[Variable], path: &Path) -> Result<(), String> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open {:?}: {}", path, e))?;
+    file.write_all(github_env(variables).as_bytes())
+        .map_err(|e| format!("Failed to write {:?}: {}", path, e))
+}
+
+/// Keep the first position of every name but its last value, like the shell would after sourcing
+pub fn deduplicate(variables: &[Variable]) -> Vec<(&str, &str)> {
+    let mut result: Vec<(&str, &str)> = Vec::new();
+    for (name, value) in variables {
+        match result.iter_mut().find(|(existing, _)| existing == name) {
+            Some(entry) => entry.1 = value,
+            None => result.push((name, value)),
+        }
+    }
+    result
+}
+
+/// Single quotes keep everything literal, a quote is closed, escaped and reopened
+fn posix_quote(value: &str) -> Zeroizing<String> {
+    let mut quoted = Zeroizing::new(String::with_capacity(value.len() + 2));
+    quoted.push('\'');
+    for c in value.chars() {
+        match c {
+            '\'' => quoted.push_str(r"'\''"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// In fish single quotes only `\\` and `\'` are escapes
+fn fish_quote(value: &str) -> Zeroizing<String> {
+    let mut quoted = Zeroizing::new(String::with_capacity(value.len() + 2));
+    quoted.push('\'');
+    for c in value.chars() {
+        if matches!(c, '\\' | '\'') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// PowerShell single quotes are escaped by doubling, including the typographic ones it also accepts
+fn powershell_quote(value: &str) -> Zeroizing<String> {
+    let mut quoted = Zeroizing::new(String::with_capacity(value.len() + 2));
+    quoted.push('\'');
+    for c in value.chars() {
+        if matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}') {
+            quoted.push(c);
+        }
+        quoted.push(c);
+    }
+    quoted.push('\'');
+    quoted
+}
+
+fn json(variables: &[(&str, &str)]) -> Zeroizing<String> {
+    if variables.is_empty() {
+        return Zeroizing::new("{}\n".to_string());
+    }
+    let mut output = Zeroizing::new(String::from("{\n"));
+    for (index, (name, value)) in variables.iter().enumerate() {
+        if index > 0 {
+            output.push_str(",\n");
+        }
+        let value =
+            Zeroizing::new(serde_json::to_string(value).expect("strings always serialize to JSON"));
+        output.push_str("  ");
+        output.push_str(&serde_json::Value::from(*name).to_string());
+        output.push_str(": ");
+        output.push_str(&value);
+    }
+    output.push_str("\n}\n");
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn variables() -> Vec<Variable> {
+        vec![
+            ("PLAIN".to_string(), Zeroizing::new("value".to_string())),
+            (
+                "QUOTES".to_string(),
+                Zeroizing::new(r#"it's "quoted" \ $HOME `x`"#.to_string()),
+            ),
+            (
+                "MULTI".to_string(),
+                Zeroizing::new("line one\nline 'two'\n".to_string()),
+            ),
+        ]
+    }
+
+    // Test that the dotenv output parses back to the same variables
+    #[test]
+    fn test_dotenv_roundtrip() {
+        let output = render(&variables(), Format::Dotenv).unwrap();
+        assert_eq!(dotenv::parse(&output).unwrap(), variables());
+    }
+
+    // Test that the JSON output is an object with all values
+    #[test]
+    fn test_json() {
+        let output = render(&variables(), Format::Json).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&output).unwrap();
+        for (name, value) in variables() {
+            assert_eq!(parsed[&name], serde_json::Value::from(value.as_str()));
+        }
+        assert_eq!(*render(&[], Format::Json).unwrap(), "{}\n");
+    }
+
+    // Test that duplicates keep the first position and the last value
+    #[test]
+    fn test_duplicates() {
+        let variables = vec![
+            ("A".to_string(), Zeroizing::new("1".to_string())),
+            ("B".to_string(), Zeroizing::new("2".to_string())),
+            ("A".to_string(), Zeroizing::new("3".to_string())),
+        ];
+        let output = render(&variables, Format::Docker).unwrap();
+        assert_eq!(*output, "A=3\nB=2\n");
+    }
+
+    // Test that Docker env files reject multi-line values
+    #[test]
+    fn test_docker_multiline() {
+        assert!(render(&variables(), Format::Docker).is_err());
+    }
+
+    // Test quoting of the shell formats
+    #[test]
+    fn test_shell_quoting() {
+        assert_eq!(*posix_quote("it's"), r"'it'\''s'");
+        assert_eq!(*fish_quote(r"it's \n"), r"'it\'s \\n'");
+        assert_eq!(*powershell_quote("it's"), "'it''s'");
+        assert_eq!(*powershell_quote("it\u{2019}s"), "'it\u{2019}\u{2019}s'");
+    }
+
+    // Test that every line of every value is masked
+    #[test]
+    fn test_github_masks() {
+        let output = render(&variables(), Format::Github).unwrap();
+        assert_eq!(
+            *output,
+            "::add-mask::value\n\
+             ::add-mask::it's \"quoted\" \\ $HOME `x`\n\
+             ::add-mask::line one\n\
+             ::add-mask::line 'two'\n"
+        );
+        assert_eq!(*github_masks("100%\r"), "::add-mask::100%25");
+    }
+
+    // Test that the GitHub format needs $GITHUB_ENV before it prints any mask
+    #[test]
+    fn test_write_github() {
+        let mut out = Vec::new();
+        assert!(write(&variables(), Format::Github, None, &mut out).is_err());
+        assert!(out.is_empty());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("github_env");
+        write(&variables(), Format::Github, Some(&path), &mut out).unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .starts_with("::add-mask::value\n"));
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .starts_with("PLAIN=value\n"));
+
+        let mut out = Vec::new();
+        let missing = dir.path().join("missing/github_env");
+        assert!(write(&variables(), Format::Github, Some(&missing), &mut out).is_err());
+        assert!(out.is_empty());
+    }
+
+    // Test that $GITHUB_ENV gets single-line assignments and heredocs for multi-line values
+    #[test]
+    fn test_append_github_env() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("github_env");
+        fs::write(&path, "EXISTING=1\n").unwrap();
+
+        append_github_env(&variables(), &path).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines[0], "EXISTING=1");
+        assert_eq!(lines[1], "PLAIN=value");
+        assert_eq!(lines[2], r#"QUOTES=it's "quoted" \ $HOME `x`"#);
+
+        let (name, delimiter) = lines[3].split_once("<<").unwrap();
+        assert_eq!(name, "MULTI");
+        assert!(delimiter.starts_with("ghadelimiter_"));
+        assert_eq!(lines[4..], ["line one", "line 'two'", "", delimiter]);
+    }
+
+    // Test that GitLab reports reject multi-line values and oversized files
+    #[test]
+    fn test_gitlab() {
+        let variables = vec![(
+            "TOKEN".to_string(),
+            Zeroizing::new("glpat-0123456789".to_string()),
+        )];
+        assert_eq!(
+            *render(&variables, Format::Gitlab).unwrap(),
+            "TOKEN=glpat-0123456789\n"
+        );
+        assert!(gitlab_warnings(&variables).is_empty());
+
+        assert!(render(&self::variables(), Format::Gitlab).is_err());
+        let large = vec![(
+            "LARGE".to_string(),
+            Zeroizing::new("x".repeat(GITLAB_MAX_SIZE)),
+        )];
+        assert!(render(&large, Format::Gitlab).is_err());
+    }
+
+    // Test the warnings for values GitLab cannot mask
+    #[test]
+    fn test_gitlab_warnings() {
+        let variables = vec![
+            ("SHORT".to_string(), Zeroizing::new("abc".to_string())),
+            (
+                "SPACE".to_string(),
+                Zeroizing::new("with a space".to_string()),
+            ),
+            (
+                "PADDED".to_string(),
+                Zeroizing::new(" 0123456789".to_string()),
+            ),
+        ];
+        let warnings = gitlab_warnings(&variables);
+        assert_eq!(warnings.len(), 4);
+        assert!(warnings[0].contains("SHORT") && warnings[0].contains("shorter"));
+        assert!(warnings[1].contains("SPACE") && warnings[1].contains("' '"));
+        assert!(warnings[2].contains("PADDED") && warnings[2].contains("whitespace"));
+
+        let many: Vec<_> = (0..=GITLAB_MAX_VARIABLES)
+            .map(|i| {
+                (
+                    format!("VAR_{}", i),
+                    Zeroizing::new("0123456789".to_string()),
+                )
+            })
+            .collect();
+        assert_eq!(gitlab_warnings(&many).len(), 1);
+    }
+
+    // Test that bash reads back every value unchanged
+    #[test]
+    fn test_bash_roundtrip() {
+        let Ok(_) = Command::new("bash").arg("-c").arg("true").status() else {
+            return;
+        };
+        let mut script = render(&variables(), Format::Bash).unwrap();
+        for (name, _) in variables() {
+            script.push_str(&format!("printf '%s\\0' \"${}\"\n", name));
+        }
+        let output = Command::new("bash").arg("-c").arg(script).output().unwrap();
+        let values: Vec<String> = output
+            .stdout
+            .split(|b| *b == 0)
+            .filter(|value| !value.is_empty())
+            .map(|value| String::from_utf8(value.to_vec()).unwrap())
+            .collect();
+        let expected: Vec<String> = variables()
+            .into_iter()
+            .map(|(_, value)| value.to_string())
+            .collect();
+        assert_eq!(values, expected);
+    }
+}
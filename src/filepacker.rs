@@ -1,23 +1,111 @@
-use bincode;
+use crate::fsutil;
+use crate::secret;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use log::debug;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
 use std::fs;
-use std::path::Path;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use zeroize::{Zeroize, Zeroizing};
 
-#[derive(Serialize, Deserialize, Debug, bincode::Encode, bincode::Decode)]
+/// File inside a folder environment with gitignore-style rules excluding files from the pack
+pub const IGNORE_FILE: &str = ".envbuddelignore";
+
+/// A file, folder or link inside a folder pack
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct PackEntry {
+    /// Path relative to the root of the pack
+    pub path: PathBuf,
+    pub entry_type: tar::EntryType,
+    pub mode: u32,
+    /// File content or link target
+    pub data: Vec<u8>,
+}
+
+/// Modification times recorded for the files of a folder pack
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mtime {
+    /// Times of the files on disk, restored by `decrypt`
+    #[default]
+    Preserve,
+    /// No times, so identical content yields an identical pack
+    Zero,
+}
+
+/// How folder environments are packed and unpacked
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct PackOptions {
+    pub mtime: Mtime,
+    /// Modes restored for paths inside the folder instead of the private defaults
+    pub modes: BTreeMap<PathBuf, u32>,
+    /// Gitignore-style patterns excluding files from the pack, applied after `.envbuddelignore`
+    pub exclude: Vec<String>,
+}
+
+impl PackOptions {
+    /// Mode of an unpacked entry: the configured override,
+    /// otherwise 0700 for folders and executables and 0600 for other files.
+    /// Links keep the mode of their target.
+    fn restored_mode(&self, path: &Path, entry_type: tar::EntryType, mode: u32) -> Option<u32> {
+        if entry_type.is_symlink() || entry_type.is_hard_link() {
+            return None;
+        }
+        if let Some((_, mode)) = self
+            .modes
+            .iter()
+            .find(|(configured, _)| normalize(configured) == path)
+        {
+            return Some(*mode);
+        }
+        if entry_type.is_dir() || mode & 0o100 != 0 {
+            Some(0o700)
+        } else {
+            Some(0o600)
+        }
+    }
+}
+
+/// Plaintext of a vault. The buffer is wiped on drop and never shown by `Debug`.
+#[derive(Serialize, Deserialize, bincode::Encode, bincode::Decode)]
 pub enum EnvironmentPack {
     Folder(Vec<u8>),
     File(Vec<u8>),
 }
 
+impl fmt::Debug for EnvironmentPack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvironmentPack::Folder(tar_bytes) => {
+                write!(f, "Folder(<{} bytes redacted>)", tar_bytes.len())
+            }
+            EnvironmentPack::File(data) => write!(f, "File(<{} bytes redacted>)", data.len()),
+        }
+    }
+}
+
+impl Drop for EnvironmentPack {
+    fn drop(&mut self) {
+        let (EnvironmentPack::Folder(data) | EnvironmentPack::File(data)) = self;
+        secret::unlock(data);
+        data.zeroize();
+    }
+}
+
 impl EnvironmentPack {
-    pub fn from_path(path: &Path) -> Result<Self, String> {
+    pub fn from_path(path: &Path, options: &PackOptions) -> Result<Self, String> {
         if path.exists() {
             if path.is_file() {
                 Ok(EnvironmentPack::File(
                     fs::read(path).map_err(|e| format!("Failed to read file: {}", e))?,
-                ))
+                )
+                .locked())
             } else if path.is_dir() {
-                Ok(EnvironmentPack::Folder(tar_directory(path)?))
+                Ok(EnvironmentPack::Folder(tar_directory(path, options)?).locked())
             } else {
                 Err(format!(
                     "Path {:?} exists but is neither a file nor a folder",
@@ -29,49 +117,623 @@ impl EnvironmentPack {
         }
     }
 
+    /// Lock the buffer in memory so the plaintext is not swapped out
+    pub fn locked(self) -> Self {
+        let (EnvironmentPack::Folder(data) | EnvironmentPack::File(data)) = &self;
+        secret::lock(data);
+        self
+    }
+
     #[allow(dead_code)]
-    pub fn content(&self) -> Result<Vec<u8>, String> {
+    pub fn content(&self) -> Result<&[u8], String> {
         match self {
-            EnvironmentPack::File(data) => Ok(data.clone()),
-            EnvironmentPack::Folder(tar_bytes) => Ok(tar_bytes.clone()),
+            EnvironmentPack::File(data) => Ok(data),
+            EnvironmentPack::Folder(tar_bytes) => Ok(tar_bytes),
         }
     }
 
-    /// Unpack the EnvironmentPack into the given destination path
-    pub fn unpack(&self, dst_path: &Path) -> Result<(), String> {
+    /// Unpack the EnvironmentPack into the given destination path.
+    /// Ownership is never restored, modes are set as described by `options`.
+    pub fn unpack(&self, dst_path: &Path, options: &PackOptions) -> Result<(), String> {
         match self {
-            EnvironmentPack::File(data) => {
-                fs::write(dst_path, data)
-                    .map_err(|e| format!("Failed to write file {:?}: {}", dst_path, e))
+            EnvironmentPack::File(data) => fsutil::write_private(dst_path, data),
+            EnvironmentPack::Folder(tar_bytes) => {
+                self.check_entries()?;
+
+                let cursor = std::io::Cursor::new(tar_bytes);
+                let mut archive = tar::Archive::new(cursor);
+                archive.set_preserve_ownerships(false);
+                archive.set_preserve_mtime(options.mtime == Mtime::Preserve);
+                archive.unpack(dst_path).map_err(|e| {
+                    format!("Failed to unpack TAR archive to {:?}: {}", dst_path, e)
+                })?;
+
+                // The plaintext is only for the owner, whatever modes the archive recorded
+                fsutil::restrict(dst_path)?;
+                fsutil::set_mode(dst_path, 0o700)?;
+                for entry in self.entries()? {
+                    if let Some(mode) =
+                        options.restored_mode(&entry.path, entry.entry_type, entry.mode)
+                    {
+                        fsutil::set_mode(&dst_path.join(&entry.path), mode)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Reject absolute paths, `..` components, device nodes, links pointing outside the pack
+    /// and entries resolved through another symlink of the pack. The error lists every offending entry.
+    pub fn check_entries(&self) -> Result<(), String> {
+        let tar_bytes = match self {
+            EnvironmentPack::File(_) => return Ok(()),
+            EnvironmentPack::Folder(tar_bytes) => tar_bytes,
+        };
+
+        let cursor = std::io::Cursor::new(tar_bytes);
+        let mut archive = tar::Archive::new(cursor);
+        let entries = archive
+            .entries()
+            .map_err(|e| format!("Failed to read TAR archive: {}", e))?;
+
+        let mut checked = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Failed to read TAR entry: {}", e))?;
+            let path = entry
+                .path()
+                .map_err(|e| format!("Failed to read TAR entry path: {}", e))?
+                .into_owned();
+            let link = entry
+                .link_name()
+                .map_err(|e| format!("Failed to read TAR link target: {}", e))?
+                .map(|link| link.into_owned());
+            checked.push((path, entry.header().entry_type(), link));
+        }
+
+        // Links are only checked as text, so nothing may be resolved through another symlink of the pack
+        let symlinks: HashSet<PathBuf> = checked
+            .iter()
+            .filter(|(_, entry_type, _)| *entry_type == tar::EntryType::Symlink)
+            .map(|(path, _, _)| normalize(path))
+            .collect();
+
+        let mut offenders = Vec::new();
+        for (path, entry_type, link) in &checked {
+            if let Some(reason) = unsafe_entry(path, *entry_type, link.as_deref())
+                .or_else(|| through_symlink(path, *entry_type, link.as_deref(), &symlinks))
+            {
+                offenders.push(format!("  - {}: {}", path.display(), reason));
+            }
+        }
+
+        if offenders.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Refusing to unpack vault, it contains unsafe entries:\n{}",
+                offenders.join("\n")
+            ))
+        }
+    }
+
+    /// All entries of a folder pack except its root, ignoring timestamps and ownership
+    pub fn entries(&self) -> Result<Vec<PackEntry>, String> {
+        let tar_bytes = match self {
+            EnvironmentPack::File(_) => return Ok(Vec::new()),
+            EnvironmentPack::Folder(tar_bytes) => tar_bytes,
+        };
+
+        let cursor = std::io::Cursor::new(tar_bytes);
+        let mut archive = tar::Archive::new(cursor);
+        let entries = archive
+            .entries()
+            .map_err(|e| format!("Failed to read TAR archive: {}", e))?;
+
+        let mut result = Vec::new();
+        for entry in entries {
+            let mut entry = entry.map_err(|e| format!("Failed to read TAR entry: {}", e))?;
+            let path = normalize(
+                &entry
+                    .path()
+                    .map_err(|e| format!("Failed to read TAR entry path: {}", e))?,
+            );
+            if path.as_os_str().is_empty() {
+                continue;
+            }
+
+            let entry_type = entry.header().entry_type();
+            let mode = entry
+                .header()
+                .mode()
+                .map_err(|e| format!("Failed to read mode of {:?}: {}", path, e))?;
+            let mut data = Vec::new();
+            if entry_type.is_symlink() || entry_type.is_hard_link() {
+                if let Some(target) = entry.link_name_bytes().map(|target| target.into_owned()) {
+                    data = target;
+                }
+            } else {
+                entry
+                    .read_to_end(&mut data)
+                    .map_err(|e| format!("Failed to read {:?} from TAR: {}", path, e))?;
+            }
+
+            result.push(PackEntry {
+                path,
+                entry_type,
+                mode,
+                data,
+            });
+        }
+        Ok(result)
+    }
+
+    /// Whether both packs hold the same files, ignoring timestamps and ownership.
+    /// Modes are ignored too since `unpack` normalizes them.
+    pub fn same_content(&self, other: &EnvironmentPack) -> Result<bool, String> {
+        match (self, other) {
+            (EnvironmentPack::File(a), EnvironmentPack::File(b)) => Ok(a == b),
+            (EnvironmentPack::Folder(_), EnvironmentPack::Folder(_)) => {
+                let (a, b) = (self.entries()?, other.entries()?);
+                Ok(a.len() == b.len()
+                    && a.iter().zip(&b).all(|(a, b)| {
+                        a.path == b.path && a.entry_type == b.entry_type && a.data == b.data
+                    }))
             }
+            _ => Ok(false),
+        }
+    }
+
+    /// Read a single file from the pack.
+    /// `path` is relative to the root of a folder pack and ignored for file packs.
+    pub fn read_file(&self, path: &str) -> Result<Zeroizing<Vec<u8>>, String> {
+        match self {
+            EnvironmentPack::File(data) => Ok(Zeroizing::new(data.clone())),
             EnvironmentPack::Folder(tar_bytes) => {
+                let wanted = normalize(Path::new(path));
                 let cursor = std::io::Cursor::new(tar_bytes);
                 let mut archive = tar::Archive::new(cursor);
-                archive
-                    .unpack(dst_path)
-                    .map_err(|e| format!("Failed to unpack TAR archive to {:?}: {}", dst_path, e))
+                let entries = archive
+                    .entries()
+                    .map_err(|e| format!("Failed to read TAR archive: {}", e))?;
+
+                for entry in entries {
+                    let mut entry =
+                        entry.map_err(|e| format!("Failed to read TAR entry: {}", e))?;
+                    let entry_path = entry
+                        .path()
+                        .map_err(|e| format!("Failed to read TAR entry path: {}", e))?;
+                    if entry.header().entry_type().is_file() && normalize(&entry_path) == wanted {
+                        let mut data = Zeroizing::new(Vec::new());
+                        entry
+                            .read_to_end(&mut data)
+                            .map_err(|e| format!("Failed to read {:?} from TAR: {}", path, e))?;
+                        return Ok(data);
+                    }
+                }
+
+                Err(format!("File {:?} not found in vault", path))
+            }
+        }
+    }
+
+    /// Stream every file of a folder pack that matches one of the glob `patterns`, or lies in a folder matching one,
+    /// to `visit` with its path and mode. Fails when a pattern matches nothing.
+    pub fn select_files(
+        &self,
+        patterns: &[String],
+        mut visit: impl FnMut(&Path, u32, &mut dyn Read) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let tar_bytes = match self {
+            EnvironmentPack::File(_) => {
+                return Err(
+                    "Vault contains a single file, not a folder. Use get or export to read it"
+                        .to_string(),
+                )
+            }
+            EnvironmentPack::Folder(tar_bytes) => tar_bytes,
+        };
+        self.check_entries()?;
+        let globs = glob_set(patterns)?;
+
+        let cursor = std::io::Cursor::new(tar_bytes);
+        let mut archive = tar::Archive::new(cursor);
+        let entries = archive
+            .entries()
+            .map_err(|e| format!("Failed to read TAR archive: {}", e))?;
+
+        let mut matched = vec![false; patterns.len()];
+        for entry in entries {
+            let mut entry = entry.map_err(|e| format!("Failed to read TAR entry: {}", e))?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = normalize(
+                &entry
+                    .path()
+                    .map_err(|e| format!("Failed to read TAR entry path: {}", e))?,
+            );
+            let mut hits = path
+                .ancestors()
+                .filter(|ancestor| !ancestor.as_os_str().is_empty())
+                .flat_map(|ancestor| globs.matches(ancestor))
+                .peekable();
+            if hits.peek().is_none() {
+                continue;
+            }
+            hits.for_each(|index| matched[index] = true);
+
+            let mode = entry
+                .header()
+                .mode()
+                .map_err(|e| format!("Failed to read mode of {:?}: {}", path, e))?;
+            visit(&path, mode, &mut entry)?;
+        }
+
+        match patterns.iter().zip(matched).find(|(_, matched)| !matched) {
+            Some((pattern, _)) => Err(format!("No file in the vault matches {:?}", pattern)),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the files selected by `patterns` below the folder `dst`, keeping their paths inside the vault.
+    /// A single pattern without glob characters naming a file is written to `dst` itself,
+    /// unless `dst` is an existing folder. Modes are restored as by `unpack`.
+    pub fn extract(
+        &self,
+        patterns: &[String],
+        dst: &Path,
+        options: &PackOptions,
+    ) -> Result<Vec<PathBuf>, String> {
+        let single = match patterns {
+            [pattern] if !dst.is_dir() && !pattern.contains(['*', '?', '[', '{']) => {
+                Some(normalize(Path::new(pattern)))
+            }
+            _ => None,
+        };
+
+        let mut written = Vec::new();
+        self.select_files(patterns, |path, mode, reader| {
+            let target = if single.as_deref() == Some(path) {
+                dst.to_path_buf()
+            } else {
+                create_private_dirs(dst, path.parent().unwrap_or(Path::new("")))?;
+                dst.join(path)
+            };
+            let mut data = Zeroizing::new(Vec::new());
+            reader
+                .read_to_end(&mut data)
+                .map_err(|e| format!("Failed to read {:?} from TAR: {}", path, e))?;
+            fsutil::write_private(&target, &data)?;
+            if let Some(mode) = options.restored_mode(path, tar::EntryType::Regular, mode) {
+                fsutil::set_mode(&target, mode)?;
+            }
+            written.push(target);
+            Ok(())
+        })?;
+        Ok(written)
+    }
+
+    /// Replace the content of a single file in the pack, adding it if it does not exist.
+    /// `path` is relative to the root of a folder pack and ignored for file packs.
+    pub fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), String> {
+        match self {
+            EnvironmentPack::File(content) => {
+                secret::unlock(content);
+                content.zeroize();
+                content.extend_from_slice(data);
+                secret::lock(content);
+                Ok(())
+            }
+            EnvironmentPack::Folder(tar_bytes) => {
+                let wanted = normalize(Path::new(path));
+                let mut builder = tar::Builder::new(Vec::new());
+                let mut replaced = false;
+
+                let cursor = std::io::Cursor::new(&*tar_bytes);
+                let mut archive = tar::Archive::new(cursor);
+                let entries = archive
+                    .entries()
+                    .map_err(|e| format!("Failed to read TAR archive: {}", e))?;
+                for entry in entries {
+                    let mut entry =
+                        entry.map_err(|e| format!("Failed to read TAR entry: {}", e))?;
+                    let entry_path = entry
+                        .path()
+                        .map_err(|e| format!("Failed to read TAR entry path: {}", e))?
+                        .into_owned();
+                    let mut header = entry.header().clone();
+                    let entry_type = header.entry_type();
+
+                    if entry_type.is_symlink() || entry_type.is_hard_link() {
+                        let target = entry
+                            .link_name()
+                            .map_err(|e| format!("Failed to read TAR link target: {}", e))?
+                            .unwrap_or_default()
+                            .into_owned();
+                        builder.append_link(&mut header, &entry_path, target)
+                    } else if entry_type.is_file() && normalize(&entry_path) == wanted {
+                        replaced = true;
+                        header.set_size(data.len() as u64);
+                        builder.append_data(&mut header, &entry_path, data)
+                    } else {
+                        let mut content = Zeroizing::new(Vec::new());
+                        entry
+                            .read_to_end(&mut content)
+                            .map_err(|e| format!("Failed to read TAR entry: {}", e))?;
+                        builder.append_data(&mut header, &entry_path, content.as_slice())
+                    }
+                    .map_err(|e| format!("Failed to write TAR entry: {}", e))?;
+                }
+
+                if !replaced {
+                    let mut header = tar::Header::new_gnu();
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_mode(0o600);
+                    header.set_size(data.len() as u64);
+                    builder
+                        .append_data(&mut header, &wanted, data)
+                        .map_err(|e| format!("Failed to write TAR entry: {}", e))?;
+                }
+
+                let rebuilt = builder
+                    .into_inner()
+                    .map_err(|e| format!("Failed to finish tar archive: {}", e))?;
+                secret::unlock(tar_bytes);
+                tar_bytes.zeroize();
+                *tar_bytes = rebuilt;
+                secret::lock(tar_bytes);
+                Ok(())
             }
         }
     }
 
     /// Serialize the EnvironmentPack to bytes (for encryption)
-    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+    pub fn to_bytes(&self) -> Result<Zeroizing<Vec<u8>>, String> {
         bincode::encode_to_vec(self, bincode::config::standard())
+            .map(Zeroizing::new)
             .map_err(|e| format!("Failed to serialize EnvironmentPack: {}", e))
     }
 
     /// Deserialize bytes back into an EnvironmentPack (after decryption)
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
         bincode::decode_from_slice(bytes, bincode::config::standard())
-            .map(|(pack, _)| pack)
+            .map(|(pack, _): (Self, _)| pack.locked())
             .map_err(|e| format!("Failed to deserialize EnvironmentPack: {}", e))
     }
 }
 
+/// Whether `path` relative to the directory `base` leaves the root `base` is relative to.
+/// Absolute paths always leave it.
+fn escapes(base: &Path, path: &Path) -> bool {
+    let mut depth = 0usize;
+    for component in base.components().chain(path.components()) {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(parent) => depth = parent,
+                None => return true,
+            },
+            Component::RootDir | Component::Prefix(_) => return true,
+        }
+    }
+    false
+}
+
+/// Reason why an entry is not allowed in a pack, if any
+fn unsafe_entry(path: &Path, entry_type: tar::EntryType, link: Option<&Path>) -> Option<String> {
+    if path.is_absolute() || path.has_root() {
+        return Some("absolute path".to_string());
+    }
+    if path
+        .components()
+        .any(|component| component == Component::ParentDir)
+    {
+        return Some("path contains '..'".to_string());
+    }
+
+    match entry_type {
+        tar::EntryType::Char | tar::EntryType::Block => Some("device node".to_string()),
+        tar::EntryType::Fifo => Some("named pipe".to_string()),
+        tar::EntryType::Symlink => {
+            let target = link.unwrap_or(Path::new(""));
+            let base = path.parent().unwrap_or(Path::new(""));
+            escapes(base, target).then(|| {
+                format!(
+                    "symlink to {} points outside the destination",
+                    target.display()
+                )
+            })
+        }
+        tar::EntryType::Link => {
+            let target = link.unwrap_or(Path::new(""));
+            escapes(Path::new(""), target).then(|| {
+                format!(
+                    "hard link to {} points outside the destination",
+                    target.display()
+                )
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Reason why an entry is resolved through a symlink of the pack, if it is:
+/// its path lies below a symlink or its link target passes through one before the last component
+fn through_symlink(
+    path: &Path,
+    entry_type: tar::EntryType,
+    link: Option<&Path>,
+    symlinks: &HashSet<PathBuf>,
+) -> Option<String> {
+    let path = normalize(path);
+    if let Some(parent) = path
+        .ancestors()
+        .skip(1)
+        .find(|ancestor| symlinks.contains(*ancestor))
+    {
+        return Some(format!("path goes through symlink {}", parent.display()));
+    }
+
+    let target = match (entry_type, link) {
+        (tar::EntryType::Symlink, Some(link)) => path.parent().unwrap_or(Path::new("")).join(link),
+        (tar::EntryType::Link, Some(link)) => link.to_path_buf(),
+        _ => return None,
+    };
+    let components: Vec<Component> = target.components().collect();
+    let mut resolved = PathBuf::new();
+    for component in components.iter().take(components.len().saturating_sub(1)) {
+        match component {
+            Component::Normal(name) => {
+                resolved.push(name);
+                if symlinks.contains(&resolved) {
+                    return Some(format!(
+                        "link to {} goes through symlink {}",
+                        link.unwrap_or(Path::new("")).display(),
+                        resolved.display()
+                    ));
+                }
+            }
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Rules excluding files from a folder pack: `.envbuddelignore` inside the folder, then the configured patterns
+fn exclude_rules(root: &Path, options: &PackOptions) -> Result<Gitignore, String> {
+    let mut builder = GitignoreBuilder::new(root);
+    let ignore_file = root.join(IGNORE_FILE);
+    if ignore_file.is_file() {
+        if let Some(err) = builder.add(&ignore_file) {
+            return Err(format!("Invalid {:?}: {}", ignore_file, err));
+        }
+    }
+    for pattern in &options.exclude {
+        builder
+            .add_line(None, pattern)
+            .map_err(|e| format!("Invalid exclude pattern {:?}: {}", pattern, e))?;
+    }
+    builder
+        .build()
+        .map_err(|e| format!("Invalid exclude patterns: {}", e))
+}
+
+/// Collect the entries below `root.join(relative)` in name order, skipping excluded ones.
+/// Paths are relative to `root`, symlinks are not followed.
+fn walk(
+    root: &Path,
+    relative: &Path,
+    rules: &Gitignore,
+    entries: &mut Vec<(PathBuf, fs::Metadata)>,
+) -> Result<(), String> {
+    let dir = root.join(relative);
+    let mut names = fs::read_dir(&dir)
+        .map_err(|e| format!("Failed to read dir {:?}: {}", dir, e))?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Failed to read dir {:?}: {}", dir, e))?;
+    names.sort();
+
+    for name in names {
+        let path = dir.join(&name);
+        let relative = relative.join(&name);
+        let metadata = fs::symlink_metadata(&path)
+            .map_err(|e| format!("Failed to read metadata of {:?}: {}", path, e))?;
+        let is_dir = metadata.is_dir();
+        if rules.matched(&relative, is_dir).is_ignore() {
+            debug!("Excluding {} from the pack", relative.display());
+            continue;
+        }
+
+        entries.push((relative.clone(), metadata));
+        if is_dir {
+            walk(root, &relative, rules, entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Paths inside the folder that are packed, after applying the exclude rules
+pub fn packed_paths(root: &Path, options: &PackOptions) -> Result<Vec<PathBuf>, String> {
+    let mut entries = Vec::new();
+    walk(
+        root,
+        Path::new(""),
+        &exclude_rules(root, options)?,
+        &mut entries,
+    )?;
+    Ok(entries.into_iter().map(|(path, _)| path).collect())
+}
+
+/// Reason why a file below `root` must not be packed: special files and symlinks leaving `root`
+fn unsafe_file(
+    root: &Path,
+    relative: &Path,
+    metadata: &fs::Metadata,
+) -> Result<Option<String>, String> {
+    let file_type = metadata.file_type();
+    if file_type.is_symlink() {
+        let path = root.join(relative);
+        let target = fs::read_link(&path)
+            .map_err(|e| format!("Failed to read symlink {:?}: {}", path, e))?;
+        if escapes(relative.parent().unwrap_or(Path::new("")), &target) {
+            return Ok(Some(format!(
+                "symlink to {} points outside the environment",
+                target.display()
+            )));
+        }
+    } else if !file_type.is_dir() && !file_type.is_file() {
+        return Ok(Some("neither file, folder nor symlink".to_string()));
+    }
+    Ok(None)
+}
+
+/// Glob patterns matching paths inside a folder pack. `*` does not match `/`, `**` does.
+fn glob_set(patterns: &[String]) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let normalized = normalize(Path::new(pattern));
+        let glob = GlobBuilder::new(&normalized.to_string_lossy())
+            .literal_separator(true)
+            .build()
+            .map_err(|e| format!("Invalid pattern {:?}: {}", pattern, e))?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map_err(|e| format!("Invalid patterns: {}", e))
+}
+
+/// Create `base` and the folders of `relative` below it that do not exist yet, accessible only by the owner
+fn create_private_dirs(base: &Path, relative: &Path) -> Result<(), String> {
+    let mut dirs: Vec<PathBuf> = relative.ancestors().map(|dir| base.join(dir)).collect();
+    dirs.reverse();
+    for dir in dirs {
+        if !dir.exists() {
+            fs::create_dir_all(&dir)
+                .map_err(|e| format!("Failed to create folder {:?}: {}", dir, e))?;
+            fsutil::set_mode(&dir, 0o700)?;
+        }
+    }
+    Ok(())
+}
+
+/// Strip `.` components so `./a/b` and `a/b` compare equal
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| !matches!(component, Component::CurDir))
+        .collect()
+}
+
 /// Create a TAR archive in memory from a directory
 /// `dir_path` should be the path to the directory
 /// Returns a Vec<u8> containing the TAR archive
-pub fn tar_directory(dir_path: &Path) -> Result<Vec<u8>, String> {
+pub fn tar_directory(dir_path: &Path, options: &PackOptions) -> Result<Vec<u8>, String> {
     // Check that the path exists and is a directory
     let metadata = fs::metadata(dir_path).map_err(|e| {
         format!(
@@ -85,17 +747,471 @@ pub fn tar_directory(dir_path: &Path) -> Result<Vec<u8>, String> {
         return Err(format!("Path '{}' is not a directory", dir_path.display()));
     }
 
-    // Create an in-memory buffer
-    let tar_buffer = Vec::new();
-    let mut tar_builder = tar::Builder::new(tar_buffer);
+    let mut entries = Vec::new();
+    walk(
+        dir_path,
+        Path::new(""),
+        &exclude_rules(dir_path, options)?,
+        &mut entries,
+    )?;
 
-    // Recursively append all files and subdirectories
-    tar_builder
-        .append_dir_all(".", dir_path)
-        .map_err(|e| format!("Failed to append directory to tar: {}", e))?;
+    let mut offenders = Vec::new();
+    for (relative, metadata) in &entries {
+        if let Some(reason) = unsafe_file(dir_path, relative, metadata)? {
+            offenders.push(format!("  - {}: {}", relative.display(), reason));
+        }
+    }
+    if !offenders.is_empty() {
+        return Err(format!(
+            "Refusing to pack {:?}, it contains unsafe entries:\n{}",
+            dir_path,
+            offenders.join("\n")
+        ));
+    }
+
+    let mut tar_builder = tar::Builder::new(Vec::new());
+    for (relative, metadata) in &entries {
+        append_entry(&mut tar_builder, dir_path, relative, metadata, options)?;
+    }
 
     // Finish the archive and take ownership of the underlying buffer
     tar_builder
         .into_inner()
         .map_err(|e| format!("Failed to finish tar archive: {}", e))
 }
+
+/// Append a single entry below `root`.
+/// Modes are recorded explicitly, ownership is never recorded and symlinks are stored as links.
+fn append_entry(
+    builder: &mut tar::Builder<Vec<u8>>,
+    root: &Path,
+    relative: &Path,
+    metadata: &fs::Metadata,
+    options: &PackOptions,
+) -> Result<(), String> {
+    let path = root.join(relative);
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(file_mode(metadata));
+    // Ownership is never recorded
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(match options.mtime {
+        Mtime::Preserve => metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |duration| duration.as_secs()),
+        Mtime::Zero => 0,
+    });
+    header.set_size(0);
+
+    if metadata.is_symlink() {
+        let target = fs::read_link(&path)
+            .map_err(|e| format!("Failed to read symlink {:?}: {}", path, e))?;
+        header.set_entry_type(tar::EntryType::Symlink);
+        builder.append_link(&mut header, relative, target)
+    } else if metadata.is_dir() {
+        header.set_entry_type(tar::EntryType::Directory);
+        builder.append_data(&mut header, relative, std::io::empty())
+    } else {
+        let data = Zeroizing::new(
+            fs::read(&path).map_err(|e| format!("Failed to read {:?}: {}", path, e))?,
+        );
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(data.len() as u64);
+        builder.append_data(&mut header, relative, data.as_slice())
+    }
+    .map_err(|e| format!("Failed to append {:?} to tar: {}", path, e))
+}
+
+/// Permission bits of a file to record in the pack
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o7777
+    }
+    #[cfg(not(unix))]
+    {
+        if metadata.is_dir() {
+            0o700
+        } else {
+            0o600
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    /// Build a folder pack with a single entry, bypassing the path checks of the tar crate
+    fn crafted_pack(path: &str, entry_type: tar::EntryType, link: Option<&str>) -> EnvironmentPack {
+        crafted_entries(&[(path, entry_type, link)])
+    }
+
+    /// Build a folder pack with the given entries in order, bypassing the path checks of the tar crate
+    fn crafted_entries(entries: &[(&str, tar::EntryType, Option<&str>)]) -> EnvironmentPack {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, entry_type, link) in entries {
+            let mut header = tar::Header::new_old();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            if let Some(link) = link {
+                header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+            }
+            header.set_entry_type(*entry_type);
+            header.set_mode(0o600);
+            header.set_size(0);
+            header.set_cksum();
+            builder.append(&header, std::io::empty()).unwrap();
+        }
+        EnvironmentPack::Folder(builder.into_inner().unwrap())
+    }
+
+    // Test that a regular folder survives pack and unpack
+    #[test]
+    fn test_pack_unpack_roundtrip() {
+        let src = tempdir().unwrap();
+        fs::create_dir(src.path().join("sub")).unwrap();
+        fs::write(src.path().join("sub/secret"), "secret").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("sub/secret", src.path().join("link")).unwrap();
+
+        let pack = EnvironmentPack::from_path(src.path(), &PackOptions::default()).unwrap();
+        let dst = tempdir().unwrap();
+        pack.unpack(dst.path(), &PackOptions::default()).unwrap();
+        assert_eq!(
+            fs::read_to_string(dst.path().join("sub/secret")).unwrap(),
+            "secret"
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            fs::read_link(dst.path().join("link")).unwrap(),
+            Path::new("sub/secret")
+        );
+    }
+
+    // Test that Debug never shows the plaintext
+    #[test]
+    fn test_debug_redacted() {
+        let pack = EnvironmentPack::File(b"PASSWORD=hunter2".to_vec());
+        let debug = format!("{:?}", pack);
+        assert_eq!(debug, "File(<16 bytes redacted>)");
+
+        let pack = EnvironmentPack::from_bytes(&pack.to_bytes().unwrap()).unwrap();
+        assert!(!format!("{:?}", Some(pack)).contains("hunter2"));
+    }
+
+    // Test that replacing the content of a file pack keeps only the new content
+    #[test]
+    fn test_write_file_replaces_content() {
+        let mut pack = EnvironmentPack::File(b"A=a much longer old value".to_vec()).locked();
+        pack.write_file("", b"A=1").unwrap();
+        assert_eq!(pack.content().unwrap(), b"A=1");
+    }
+
+    // Test that zeroed times make packing reproducible and no ownership is recorded
+    #[test]
+    fn test_pack_deterministic() {
+        let options = PackOptions {
+            mtime: Mtime::Zero,
+            ..PackOptions::default()
+        };
+        let src = tempdir().unwrap();
+        fs::create_dir(src.path().join("b")).unwrap();
+        fs::write(src.path().join("b/secret"), "secret").unwrap();
+        fs::write(src.path().join("a"), "a").unwrap();
+        let first = tar_directory(src.path(), &options).unwrap();
+
+        let file = fs::File::options()
+            .write(true)
+            .open(src.path().join("a"))
+            .unwrap();
+        file.set_modified(UNIX_EPOCH + std::time::Duration::from_secs(1_000_000))
+            .unwrap();
+        assert_eq!(tar_directory(src.path(), &options).unwrap(), first);
+        assert_ne!(
+            tar_directory(src.path(), &PackOptions::default()).unwrap(),
+            first
+        );
+
+        let mut archive = tar::Archive::new(std::io::Cursor::new(first));
+        let paths: Vec<PathBuf> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                assert_eq!(entry.header().uid().unwrap(), 0);
+                assert_eq!(entry.header().mtime().unwrap(), 0);
+                normalize(&entry.path().unwrap())
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("a"),
+                PathBuf::from("b"),
+                PathBuf::from("b/secret")
+            ]
+        );
+    }
+
+    // Test that .envbuddelignore and configured patterns exclude files from the pack
+    #[test]
+    fn test_exclude() {
+        let src = tempdir().unwrap();
+        fs::create_dir_all(src.path().join(".git/objects")).unwrap();
+        fs::create_dir(src.path().join("sub")).unwrap();
+        for file in [
+            ".git/config",
+            ".DS_Store",
+            "secret",
+            "secret.swp",
+            "keep.swp",
+            "sub/.DS_Store",
+            "sub/token",
+        ] {
+            fs::write(src.path().join(file), file).unwrap();
+        }
+        fs::write(src.path().join(IGNORE_FILE), "*.swp\n!keep.swp\n.git/\n").unwrap();
+        let options = PackOptions {
+            exclude: vec![".DS_Store".to_string()],
+            ..PackOptions::default()
+        };
+
+        let expected: Vec<PathBuf> = [IGNORE_FILE, "keep.swp", "secret", "sub", "sub/token"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(packed_paths(src.path(), &options).unwrap(), expected);
+
+        let pack = EnvironmentPack::from_path(src.path(), &options).unwrap();
+        let paths: Vec<PathBuf> = pack
+            .entries()
+            .unwrap()
+            .into_iter()
+            .map(|e| e.path)
+            .collect();
+        assert_eq!(paths, expected);
+    }
+
+    // Test that excluded special files do not prevent packing
+    #[cfg(unix)]
+    #[test]
+    fn test_exclude_unsafe_entry() {
+        let src = tempdir().unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", src.path().join("passwd")).unwrap();
+        let options = PackOptions {
+            exclude: vec!["passwd".to_string()],
+            ..PackOptions::default()
+        };
+        assert!(EnvironmentPack::from_path(src.path(), &options).is_ok());
+    }
+
+    // Test that unpacked files are private unless the configuration overrides their mode
+    #[cfg(unix)]
+    #[test]
+    fn test_unpack_modes() {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+
+        let src = tempdir().unwrap();
+        fs::create_dir(src.path().join("ssh")).unwrap();
+        for (file, file_mode) in [
+            ("ssh/id_rsa", 0o644),
+            ("ssh/id_rsa.pub", 0o600),
+            ("run", 0o755),
+        ] {
+            fs::write(src.path().join(file), file).unwrap();
+            fsutil::set_mode(&src.path().join(file), file_mode).unwrap();
+        }
+        fsutil::set_mode(&src.path().join("ssh"), 0o755).unwrap();
+        let pack = EnvironmentPack::from_path(src.path(), &PackOptions::default()).unwrap();
+        let recorded: Vec<u32> = pack.entries().unwrap().iter().map(|e| e.mode).collect();
+        assert_eq!(recorded, vec![0o755, 0o755, 0o644, 0o600]);
+
+        let options = PackOptions {
+            modes: BTreeMap::from([(PathBuf::from("./ssh/id_rsa.pub"), 0o644)]),
+            ..PackOptions::default()
+        };
+        let dst = tempdir().unwrap();
+        let env = dst.path().join("env");
+        pack.unpack(&env, &options).unwrap();
+        assert_eq!(mode(&env), 0o700);
+        assert_eq!(mode(&env.join("ssh")), 0o700);
+        assert_eq!(mode(&env.join("ssh/id_rsa")), 0o600);
+        assert_eq!(mode(&env.join("ssh/id_rsa.pub")), 0o644);
+        assert_eq!(mode(&env.join("run")), 0o700);
+    }
+
+    // Test that globs and folders select files and that unmatched patterns fail
+    #[test]
+    fn test_select_files() {
+        let src = tempdir().unwrap();
+        fs::create_dir_all(src.path().join("k8s/prod")).unwrap();
+        for file in [
+            "kubeconfig.yaml",
+            "k8s/a.yaml",
+            "k8s/b.txt",
+            "k8s/prod/c.yaml",
+        ] {
+            fs::write(src.path().join(file), file).unwrap();
+        }
+        let pack = EnvironmentPack::from_path(src.path(), &PackOptions::default()).unwrap();
+        let select = |patterns: &[&str]| {
+            let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+            let mut selected = Vec::new();
+            pack.select_files(&patterns, |path, _, reader| {
+                let mut content = String::new();
+                reader.read_to_string(&mut content).unwrap();
+                assert_eq!(Path::new(&content), path);
+                selected.push(content);
+                Ok(())
+            })
+            .map(|_| selected)
+        };
+
+        assert_eq!(select(&["./kubeconfig.yaml"]).unwrap(), ["kubeconfig.yaml"]);
+        assert_eq!(select(&["k8s/*.yaml"]).unwrap(), ["k8s/a.yaml"]);
+        assert_eq!(
+            select(&["k8s/**/*.yaml"]).unwrap(),
+            ["k8s/a.yaml", "k8s/prod/c.yaml"]
+        );
+        assert_eq!(select(&["k8s/prod"]).unwrap(), ["k8s/prod/c.yaml"]);
+        assert!(select(&["kubeconfig.yaml", "missing"])
+            .unwrap_err()
+            .contains("\"missing\""));
+
+        let file = EnvironmentPack::File(b"A=1".to_vec());
+        assert!(file
+            .select_files(&["A".to_string()], |_, _, _| Ok(()))
+            .is_err());
+    }
+
+    // Test that extracted files are private and a single file can be written to the destination path
+    #[cfg(unix)]
+    #[test]
+    fn test_extract() {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+
+        let src = tempdir().unwrap();
+        fs::create_dir_all(src.path().join("k8s/prod")).unwrap();
+        for file in ["kubeconfig.yaml", "other", "k8s/prod/c.yaml"] {
+            fs::write(src.path().join(file), file).unwrap();
+            fsutil::set_mode(&src.path().join(file), 0o644).unwrap();
+        }
+        let pack = EnvironmentPack::from_path(src.path(), &PackOptions::default()).unwrap();
+        let dst = tempdir().unwrap();
+
+        let config = dst.path().join("config");
+        let written = pack
+            .extract(
+                &["kubeconfig.yaml".to_string()],
+                &config,
+                &PackOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(written, vec![config.clone()]);
+        assert_eq!(fs::read_to_string(&config).unwrap(), "kubeconfig.yaml");
+        assert_eq!(mode(&config), 0o600);
+
+        let out = dst.path().join("out");
+        pack.extract(&["k8s/**".to_string()], &out, &PackOptions::default())
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(out.join("k8s/prod/c.yaml")).unwrap(),
+            "k8s/prod/c.yaml"
+        );
+        assert_eq!(mode(&out), 0o700);
+        assert_eq!(mode(&out.join("k8s/prod")), 0o700);
+        assert!(!out.join("other").exists());
+
+        // An existing folder receives the file under its path inside the vault
+        pack.extract(&["other".to_string()], &out, &PackOptions::default())
+            .unwrap();
+        assert_eq!(fs::read_to_string(out.join("other")).unwrap(), "other");
+    }
+
+    // Test that entries leaving the destination are rejected and listed
+    #[test]
+    fn test_unpack_rejects_traversal() {
+        let cases = [
+            crafted_pack("../evil", tar::EntryType::Regular, None),
+            crafted_pack("/etc/evil", tar::EntryType::Regular, None),
+            crafted_pack("a/../../evil", tar::EntryType::Regular, None),
+            crafted_pack("link", tar::EntryType::Symlink, Some("/etc")),
+            crafted_pack("sub/link", tar::EntryType::Symlink, Some("../../etc")),
+            crafted_pack("hard", tar::EntryType::Link, Some("../outside")),
+            crafted_pack("dev", tar::EntryType::Char, None),
+        ];
+
+        for pack in cases {
+            let dst = tempdir().unwrap();
+            let err = pack
+                .unpack(&dst.path().join("env"), &PackOptions::default())
+                .unwrap_err();
+            assert!(err.contains("unsafe entries"), "{}", err);
+            assert!(!dst.path().join("env").exists());
+        }
+    }
+
+    // Test that symlinks staying inside the destination are allowed
+    #[test]
+    fn test_check_entries_allows_inner_symlink() {
+        let pack = crafted_pack("sub/link", tar::EntryType::Symlink, Some("../other"));
+        assert!(pack.check_entries().is_ok());
+    }
+
+    // Test that chained symlinks which only escape together are rejected
+    #[test]
+    fn test_unpack_rejects_chained_symlinks() {
+        use tar::EntryType::{Link, Regular, Symlink};
+        let cases = [
+            vec![("a", Symlink, Some(".")), ("a/b", Symlink, Some(".."))],
+            vec![("a", Symlink, Some(".")), ("a/b/c", Symlink, Some("../.."))],
+            vec![("b", Symlink, Some("a/..")), ("a", Symlink, Some("."))],
+            vec![("a", Symlink, Some(".")), ("h", Link, Some("a/../x"))],
+            vec![("a", Symlink, Some("sub")), ("a/file", Regular, None)],
+        ];
+
+        for entries in cases {
+            let pack = crafted_entries(&entries);
+            let dst = tempdir().unwrap();
+            let err = pack
+                .unpack(&dst.path().join("env"), &PackOptions::default())
+                .unwrap_err();
+            assert!(err.contains("goes through symlink"), "{}", err);
+            assert!(!dst.path().join("env").exists());
+        }
+
+        // A link to another link is fine as long as nothing is resolved below it
+        let pack = crafted_entries(&[
+            ("a", Symlink, Some("sub")),
+            ("b", Symlink, Some("a")),
+            ("sub/c", Symlink, Some("../b")),
+        ]);
+        assert!(pack.check_entries().is_ok());
+    }
+
+    // Test that packing refuses symlinks leaving the folder
+    #[cfg(unix)]
+    #[test]
+    fn test_pack_rejects_outer_symlink() {
+        let src = tempdir().unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", src.path().join("passwd")).unwrap();
+
+        let err = EnvironmentPack::from_path(src.path(), &PackOptions::default()).unwrap_err();
+        assert!(err.contains("passwd"), "{}", err);
+    }
+
+    // Test lexical escape detection
+    #[test]
+    fn test_escapes() {
+        assert!(!escapes(Path::new("a/b"), Path::new("../c")));
+        assert!(escapes(Path::new("a"), Path::new("../../c")));
+        assert!(escapes(Path::new(""), Path::new("/abs")));
+        assert!(!escapes(Path::new(""), Path::new("./a/./b")));
+    }
+}
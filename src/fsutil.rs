@@ -0,0 +1,315 @@
+use log::{debug, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+/// Stage `contents` in a temporary file next to `path`.
+/// Nothing is visible at `path` until the returned file is persisted.
+pub fn stage(path: &Path, contents: &[u8]) -> Result<NamedTempFile, String> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let mut file = NamedTempFile::new_in(parent)
+        .map_err(|e| format!("Failed to create temporary file for {:?}: {}", path, e))?;
+    file.write_all(contents)
+        .and_then(|_| file.as_file().sync_all())
+        .map_err(|e| format!("Failed to write temporary file for {:?}: {}", path, e))?;
+    Ok(file)
+}
+
+/// Atomically replace `path` with a staged file
+pub fn commit(file: NamedTempFile, path: &Path) -> Result<(), String> {
+    file.persist(path)
+        .map(|_| ())
+        .map_err(|e| format!("Failed to replace {:?}: {}", path, e.error))
+}
+
+/// Write `contents` to `path` so readers either see the old or the new content
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), String> {
+    commit(stage(path, contents)?, path)
+}
+
+/// Write `contents` atomically to a file only the owner can read and write (0600)
+pub fn write_private(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let file = stage(path, contents)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.as_file()
+            .set_permissions(fs::Permissions::from_mode(0o600))
+            .map_err(|e| format!("Failed to set permissions of {:?}: {}", path, e))?;
+    }
+    commit(file, path)
+}
+
+/// What to do when a secret file can be read by other users
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PermissionPolicy {
+    /// Log a warning and continue
+    #[default]
+    Warn,
+    /// Refuse to use the file
+    Deny,
+    /// Do not check
+    Ignore,
+}
+
+impl PermissionPolicy {
+    /// Apply the policy to the problem found with `path`, if any
+    pub fn enforce(self, path: &Path, problem: Option<String>) -> Result<(), String> {
+        let Some(problem) = problem else {
+            return Ok(());
+        };
+        debug!("{:?} {}", path, problem);
+        let message = format!(
+            "{:?} {}. Run `chmod go-rwx {}` to fix it",
+            path,
+            problem,
+            path.display()
+        );
+        match self {
+            PermissionPolicy::Warn => {
+                warn!("{}", message);
+                Ok(())
+            }
+            PermissionPolicy::Deny => Err(message),
+            PermissionPolicy::Ignore => Ok(()),
+        }
+    }
+}
+
+/// Describe why a file with this metadata is not private: access for group/others or another owner
+#[cfg(unix)]
+pub fn private_problem(metadata: &fs::Metadata) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    // SAFETY: geteuid has no preconditions and cannot fail
+    let euid = unsafe { libc::geteuid() };
+    if metadata.uid() != euid {
+        Some(format!("is owned by uid {}, not by you", metadata.uid()))
+    } else if metadata.mode() & 0o077 != 0 {
+        Some(format!(
+            "is accessible by group/others (mode {:o})",
+            metadata.mode() & 0o777
+        ))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+pub fn private_problem(_metadata: &fs::Metadata) -> Option<String> {
+    None
+}
+
+/// Problems of every file and folder below `path`. Symlinks are not followed.
+pub fn private_problems(path: &Path) -> Result<Vec<(PathBuf, String)>, String> {
+    let metadata = fs::symlink_metadata(path)
+        .map_err(|e| format!("Failed to read metadata of {:?}: {}", path, e))?;
+    let mut problems = Vec::new();
+    if metadata.is_symlink() {
+        return Ok(problems);
+    }
+    if let Some(problem) = private_problem(&metadata) {
+        problems.push((path.to_path_buf(), problem));
+    }
+    if metadata.is_dir() {
+        let entries =
+            fs::read_dir(path).map_err(|e| format!("Failed to read dir {:?}: {}", path, e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Failed to read dir {:?}: {}", path, e))?;
+            problems.extend(private_problems(&entry.path())?);
+        }
+    }
+    Ok(problems)
+}
+
+/// Remove all permissions of group and others below `path`. Symlinks are not followed.
+pub fn restrict(path: &Path) -> Result<(), String> {
+    let metadata = fs::symlink_metadata(path)
+        .map_err(|e| format!("Failed to read metadata of {:?}: {}", path, e))?;
+    if metadata.is_symlink() {
+        return Ok(());
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        set_mode(path, metadata.permissions().mode() & 0o700)?;
+    }
+    if metadata.is_dir() {
+        let entries =
+            fs::read_dir(path).map_err(|e| format!("Failed to read dir {:?}: {}", path, e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Failed to read dir {:?}: {}", path, e))?;
+            restrict(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Set the permission bits of `path`. Does nothing on platforms without POSIX modes.
+pub fn set_mode(path: &Path, mode: u32) -> Result<(), String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .map_err(|e| format!("Failed to set permissions of {:?}: {}", path, e))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (path, mode);
+    Ok(())
+}
+
+/// Walk up from `start` and return the first directory where `matches` accepts `dir.join(name)`
+pub fn find_upwards(start: &Path, name: &str, matches: impl Fn(&Path) -> bool) -> Option<PathBuf> {
+    let mut current = start.canonicalize().ok()?;
+    loop {
+        let candidate = current.join(name);
+        trace!("{} candidate: {:?}", name, candidate);
+        if matches(&candidate) {
+            return Some(current);
+        }
+
+        // If we are at the root, stop
+        if !current.pop() {
+            return None;
+        }
+    }
+}
+
+/// Overwrite every regular file below `path` with zeros so the plaintext does not linger on disk.
+/// Symlinks are not followed. The files are not removed.
+pub fn shred(path: &Path) -> Result<(), String> {
+    let metadata = fs::symlink_metadata(path)
+        .map_err(|e| format!("Failed to read metadata of {:?}: {}", path, e))?;
+    if metadata.is_dir() {
+        let entries =
+            fs::read_dir(path).map_err(|e| format!("Failed to read dir {:?}: {}", path, e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Failed to read dir {:?}: {}", path, e))?;
+            shred(&entry.path())?;
+        }
+    } else if metadata.is_file() {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open {:?}: {}", path, e))?;
+        file.write_all(&vec![0u8; metadata.len() as usize])
+            .and_then(|_| file.sync_all())
+            .map_err(|e| format!("Failed to overwrite {:?}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    // Test that write_atomic replaces the file content
+    #[test]
+    fn test_write_atomic_replaces() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, "old").unwrap();
+
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    // Test that shred overwrites nested files with zeros
+    #[test]
+    fn test_shred() {
+        let dir = tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/secret"), "secret").unwrap();
+
+        shred(dir.path()).unwrap();
+        assert_eq!(
+            fs::read(dir.path().join("sub/secret")).unwrap(),
+            vec![0u8; 6]
+        );
+    }
+
+    // Test that private files are created with 0600, also when replacing a readable file
+    #[cfg(unix)]
+    #[test]
+    fn test_write_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("key");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private(&path, b"secret").unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(private_problem(&metadata), None);
+    }
+
+    // Test that restrict removes group and other permissions recursively
+    #[cfg(unix)]
+    #[test]
+    fn test_restrict() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let root = dir.path().join("env");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("sub/secret"), "secret").unwrap();
+        for path in [&root, &root.join("sub"), &root.join("sub/secret")] {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        assert_eq!(private_problems(&root).unwrap().len(), 3);
+
+        restrict(&root).unwrap();
+        assert!(private_problems(&root).unwrap().is_empty());
+        let mode = fs::metadata(root.join("sub/secret"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    // Test that the deny policy turns a problem into an error
+    #[test]
+    fn test_permission_policy() {
+        let path = Path::new("key");
+        let problem = || Some("is accessible by group/others (mode 644)".to_string());
+        assert!(PermissionPolicy::Warn.enforce(path, problem()).is_ok());
+        assert!(PermissionPolicy::Deny.enforce(path, problem()).is_err());
+        assert!(PermissionPolicy::Deny.enforce(path, None).is_ok());
+    }
+
+    // Test that the upward search finds the closest directory
+    #[test]
+    fn test_find_upwards() {
+        let dir = tempdir().unwrap();
+        let nested = dir.path().join("a/b");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(dir.path().join("marker"), "").unwrap();
+
+        let found = find_upwards(&nested, "marker", Path::is_file).unwrap();
+        assert_eq!(found, dir.path().canonicalize().unwrap());
+        assert!(find_upwards(&nested, "b", Path::is_file).is_none());
+    }
+
+    // Test that a staged file is not visible until committed
+    #[test]
+    fn test_stage_is_invisible() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("file");
+
+        let staged = stage(&path, b"content").unwrap();
+        assert!(!path.exists());
+        commit(staged, &path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "content");
+    }
+}
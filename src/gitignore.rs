@@ -1,29 +1,11 @@
+use crate::fsutil;
 use log::{debug, info, trace, warn};
 use std::path::{Path, PathBuf};
 use std::{env, fs};
 
 pub fn find_repo() -> Result<PathBuf, String> {
-    let mut current = Path::new(".")
-        .canonicalize()
-        .map_err(|e| format!("{:?}", e))?
-        .to_path_buf();
-
-    loop {
-        let parent = current.clone();
-        let candidate = current.join(".git");
-        trace!("repo path candidate: {:?}", candidate);
-        if candidate.exists() && candidate.is_dir() {
-            return Ok(parent);
-        }
-
-        // If we are at the root, stop
-        match current.parent() {
-            Some(parent) => current = parent.to_path_buf(),
-            None => break,
-        }
-    }
-
-    Err("No git repository found in current or parent directories".to_string())
+    fsutil::find_upwards(Path::new("."), ".git", Path::is_dir)
+        .ok_or_else(|| "No git repository found in current or parent directories".to_string())
 }
 
 pub fn gitignore(files: Vec<PathBuf>) -> Result<(), String> {
@@ -120,6 +102,7 @@ pub fn add_files_to_gitignore(content: &str, files: &[&str]) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[allow(unused_imports)]
     use std::fs::{self, File};
     use tempfile::TempDir;
 
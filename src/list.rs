@@ -0,0 +1,202 @@
+use crate::dotenv;
+use crate::filepacker::{EnvironmentPack, PackEntry};
+use clap::ValueEnum;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// Output formats of the `list` command
+#[derive(Eq, PartialEq, Debug, Clone, Copy, ValueEnum)]
+pub enum Format {
+    /// One line per file or variable
+    Text,
+    /// A single JSON object for scripts
+    Json,
+}
+
+/// Contents of a vault without any secret values
+#[derive(Eq, PartialEq, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Listing {
+    /// Entries of a folder vault
+    Folder { entries: Vec<Entry> },
+    /// Variable names of a dotenv file vault
+    File { variables: Vec<String> },
+}
+
+/// A file, folder or link of a folder vault
+#[derive(Eq, PartialEq, Debug, Serialize)]
+pub struct Entry {
+    pub path: String,
+    #[serde(rename = "type")]
+    pub entry_type: &'static str,
+    /// Permission bits in octal, e.g. `0600`
+    pub mode: String,
+    /// Size of the content in bytes, 0 for folders and links
+    pub size: usize,
+    /// Hex SHA-256 of the content of files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Target of links
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+impl Listing {
+    /// List the entries of a folder pack or the variable names of a file pack
+    pub fn of(pack: &EnvironmentPack) -> Result<Self, String> {
+        match pack {
+            EnvironmentPack::Folder(_) => Ok(Listing::Folder {
+                entries: pack.entries()?.iter().map(Entry::of).collect(),
+            }),
+            EnvironmentPack::File(data) => {
+                let content = std::str::from_utf8(data)
+                    .map_err(|e| format!("Environment is not valid UTF-8: {}", e))?;
+                let mut variables: Vec<String> = Vec::new();
+                for line in dotenv::lines(content)? {
+                    if let dotenv::Line::Variable { key, .. } = line {
+                        if !variables.iter().any(|name| name == key) {
+                            variables.push(key.to_string());
+                        }
+                    }
+                }
+                Ok(Listing::File { variables })
+            }
+        }
+    }
+
+    pub fn render(&self, format: Format) -> Result<String, String> {
+        match format {
+            Format::Json => serde_json::to_string_pretty(self)
+                .map(|json| json + "\n")
+                .map_err(|e| format!("Failed to serialize listing: {}", e)),
+            Format::Text => Ok(self.text()),
+        }
+    }
+
+    /// `mode size sha256 path` per entry like `ls -l`, or one variable name per line
+    fn text(&self) -> String {
+        let mut output = String::new();
+        match self {
+            Listing::Folder { entries } => {
+                for entry in entries {
+                    let mut line = format!(
+                        "{} {:>10} {:<64} {}",
+                        entry.mode,
+                        entry.size,
+                        entry.sha256.as_deref().unwrap_or("-"),
+                        entry.path
+                    );
+                    if entry.entry_type == "dir" {
+                        line.push('/');
+                    }
+                    if let Some(target) = &entry.target {
+                        line.push_str(" -> ");
+                        line.push_str(target);
+                    }
+                    output.push_str(&line);
+                    output.push('\n');
+                }
+            }
+            Listing::File { variables } => {
+                for name in variables {
+                    output.push_str(name);
+                    output.push('\n');
+                }
+            }
+        }
+        output
+    }
+}
+
+impl Entry {
+    fn of(entry: &PackEntry) -> Self {
+        let entry_type = if entry.entry_type.is_dir() {
+            "dir"
+        } else if entry.entry_type.is_symlink() {
+            "symlink"
+        } else if entry.entry_type.is_hard_link() {
+            "hardlink"
+        } else {
+            "file"
+        };
+        let is_file = entry_type == "file";
+        Entry {
+            path: entry.path.to_string_lossy().into_owned(),
+            entry_type,
+            mode: format!("{:04o}", entry.mode & 0o7777),
+            size: if is_file { entry.data.len() } else { 0 },
+            sha256: is_file.then(|| sha256(&entry.data)),
+            target: (!is_file && entry_type != "dir")
+                .then(|| String::from_utf8_lossy(&entry.data).into_owned()),
+        }
+    }
+}
+
+fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filepacker::PackOptions;
+    use std::fs;
+    use tempfile::tempdir;
+
+    // Test that folder entries are listed with mode, size and hash but without content
+    #[test]
+    fn test_folder() {
+        let dir = tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/secret"), "hello").unwrap();
+        let pack = EnvironmentPack::from_path(dir.path(), &PackOptions::default()).unwrap();
+
+        let listing = Listing::of(&pack).unwrap();
+        let Listing::Folder { entries } = &listing else {
+            panic!("expected a folder listing");
+        };
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, "sub");
+        assert_eq!(entries[0].entry_type, "dir");
+        assert_eq!(entries[0].sha256, None);
+        assert_eq!(entries[1].path, "sub/secret");
+        assert_eq!(entries[1].size, 5);
+        assert_eq!(
+            entries[1].sha256.as_deref(),
+            Some("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+        );
+
+        let text = listing.render(Format::Text).unwrap();
+        assert!(text.contains("sub/\n"));
+        assert!(!text.contains("hello"));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&listing.render(Format::Json).unwrap()).unwrap();
+        assert_eq!(json["type"], "folder");
+        assert_eq!(json["entries"][1]["path"], "sub/secret");
+        assert_eq!(json["entries"][1]["size"], 5);
+    }
+
+    // Test that file vaults list each variable name once and never a value
+    #[test]
+    fn test_file() {
+        let pack = EnvironmentPack::File(b"# comment\nA=secret\nexport B='x'\nA=other\n".to_vec());
+        let listing = Listing::of(&pack).unwrap();
+        assert_eq!(
+            listing,
+            Listing::File {
+                variables: vec!["A".to_string(), "B".to_string()]
+            }
+        );
+        assert_eq!(listing.render(Format::Text).unwrap(), "A\nB\n");
+
+        let json = listing.render(Format::Json).unwrap();
+        assert!(!json.contains("secret"));
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json["type"], "file");
+        assert_eq!(json["variables"][1], "B");
+    }
+}
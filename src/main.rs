@@ -1,14 +1,35 @@
+mod config;
 mod crypto;
+mod diff;
+mod dotenv;
+mod edit;
+mod environment;
+mod export;
 mod filepacker;
+mod fsutil;
 mod gitignore;
+mod list;
+mod protect;
+mod recipients;
+mod secret;
+mod values;
+mod vault;
 
+use crate::config::Project;
 use crate::crypto::{Key, KeySource};
+use crate::environment::Environment;
 use crate::filepacker::EnvironmentPack;
+use crate::fsutil::PermissionPolicy;
 use crate::gitignore::gitignore;
+use crate::recipients::Recipient;
+use crate::vault::VaultMode;
 use clap::{Parser, Subcommand};
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use std::fs;
+use std::io::{self, IsTerminal, Read};
 use std::path::{Path, PathBuf};
+use std::process::{self, ExitCode};
+use zeroize::Zeroizing;
 
 #[derive(Parser)]
 #[command(name = "envbuddel")]
@@ -19,21 +40,33 @@ struct Cli {
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
 
-    /// Path to the keyfile
-    #[arg(long, default_value = "vault.key")]
-    keyfile: PathBuf,
+    /// Named environment, e.g. `prod`. Uses vault.<ENV>.key, vault.<ENV>.enc, .env.<ENV> and CI_SECRET_<ENV>
+    #[arg(long, global = true)]
+    env: Option<String>,
 
-    /// Content of the key
-    #[arg(long, env = "CI_SECRET")]
+    /// Path to the keyfile [default: vault.key]
+    #[arg(long)]
+    keyfile: Option<PathBuf>,
+
+    /// Content of the key. Read from the variable named by --key-var if not given
+    #[arg(long)]
     key: Option<String>,
 
-    /// path to .env file or folder
-    #[arg(long, default_value = ".env")]
-    env_conf: PathBuf,
+    /// Environment variable holding the key [default: CI_SECRET, CI_SECRET_<ENV> with --env]
+    #[arg(long)]
+    key_var: Option<String>,
+
+    /// path to .env file or folder [default: .env]
+    #[arg(long)]
+    env_conf: Option<PathBuf>,
 
-    /// path to the vault file
-    #[arg(long, default_value = "vault.enc")]
-    vault: PathBuf,
+    /// path to the vault file [default: vault.enc]
+    #[arg(long)]
+    vault: Option<PathBuf>,
+
+    /// Gitignore-style pattern excluding files of a folder environment from the vault (can be repeated)
+    #[arg(long, global = true)]
+    exclude: Vec<String>,
 
     #[command(subcommand)]
     command: Commands,
@@ -42,109 +75,397 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Shows debug info (key, vault, environment path) to the supplied options
-    Info {},
+    Info {
+        /// Prints the keys instead of their fingerprints
+        #[arg(long)]
+        reveal: bool,
+    },
 
     /// Initializes repository. Creates a new key, environment and vault and adds entries to .gitignore
     Init {
         /// Creates folder instead of a single file for the environment
         #[arg(long)]
         folder: bool,
+
+        /// Creates a personal X25519 identity instead of a shared symmetric key
+        #[arg(long)]
+        identity: bool,
+
+        /// Protects the keyfile with a passphrase (read from ENVBUDDEL_PASSPHRASE or prompted)
+        #[arg(long)]
+        protect: bool,
+
+        /// How the environment is stored in the vault [default: blob]
+        #[arg(long, value_enum)]
+        mode: Option<VaultMode>,
+
+        /// Prints the key even when the output is not a terminal
+        #[arg(long)]
+        show_key: bool,
     },
 
     /// Encrypt the environment and stores everything in the vault
-    Encrypt {},
+    Encrypt {
+        /// How the environment is stored in the vault. Defaults to the mode of the existing vault
+        #[arg(long, value_enum)]
+        mode: Option<VaultMode>,
+    },
 
     /// Decrypts the environment from the vault and unpacks them to --env-conf path
     Decrypt {},
+
+    /// Decrypts the vault in memory and runs a command with the variables in its environment
+    Run {
+        /// Dotenv file inside a folder vault to load (can be repeated)
+        #[arg(long = "file")]
+        files: Vec<String>,
+
+        /// Command to execute, e.g. `envbuddel run -- ./deploy.sh`
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+        command: Vec<String>,
+    },
+
+    /// Decrypts the vault in memory and prints the variables for a shell or tool
+    Export {
+        /// Output format
+        #[arg(long, value_enum, default_value = "bash")]
+        format: export::Format,
+
+        /// Dotenv file inside a folder vault to export (can be repeated)
+        #[arg(long = "file")]
+        files: Vec<String>,
+    },
+
+    /// Decrypts the vault in memory and lists its files, or the variable names of a dotenv vault
+    List {
+        /// Output format
+        #[arg(long, value_enum, default_value = "text")]
+        format: list::Format,
+    },
+
+    /// Decrypts the vault in memory and prints files of a folder vault
+    Cat {
+        /// Path inside the vault. Globs like `k8s/*.yaml` select several files, a folder selects all files in it
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+
+    /// Decrypts the vault in memory and writes only the selected files of a folder vault
+    Extract {
+        /// Path inside the vault. Globs like `k8s/*.yaml` select several files, a folder selects all files in it
+        #[arg(required = true)]
+        paths: Vec<String>,
+
+        /// Destination folder. A single file without glob is written to this path unless it is a folder
+        #[arg(long)]
+        to: PathBuf,
+    },
+
+    /// Decrypts the vault in memory and compares it with --env-conf. Exits with 1 if they differ
+    Diff {
+        /// Prints the values of added, removed and changed variables
+        #[arg(long)]
+        show_values: bool,
+    },
+
+    /// Re-encrypts the vault with a new key and replaces the keyfile
+    Rotate {
+        /// Current key. Defaults to --key/CI_SECRET or the keyfile
+        #[arg(long)]
+        old_key: Option<String>,
+
+        /// Key to rotate to. Defaults to a newly generated key
+        #[arg(long)]
+        new_key: Option<String>,
+
+        /// Prints the new key even when the output is not a terminal
+        #[arg(long)]
+        show_key: bool,
+    },
+
+    /// Prints the value of a single variable without writing plaintext to disk
+    Get {
+        /// Variable name. Use `file:NAME` for a dotenv file inside a folder vault
+        name: String,
+    },
+
+    /// Sets a single variable and re-encrypts the vault without writing plaintext to disk
+    Set {
+        /// `NAME=value`, or `NAME` to read the value from stdin. Use `file:NAME` for a dotenv file inside a folder vault
+        assignment: String,
+    },
+
+    /// Opens the decrypted environment in $EDITOR and re-encrypts it when it changed
+    Edit {},
+
+    /// Manages keys
+    Key {
+        #[command(subcommand)]
+        command: KeyCommands,
+    },
+
+    /// Manages the recipients a vault is encrypted to
+    Recipients {
+        #[command(subcommand)]
+        command: RecipientsCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeyCommands {
+    /// Generates a new X25519 identity in --keyfile and prints its public recipient
+    Identity {},
+
+    /// Encrypts the key in --keyfile under a passphrase using Argon2id
+    Protect {},
+}
+
+#[derive(Subcommand)]
+enum RecipientsCommands {
+    /// Lists the recipients of the vault
+    List {},
+
+    /// Adds a recipient and re-encrypts the vault
+    Add {
+        /// Public key of the recipient (envbuddel-recipient-...)
+        recipient: String,
+    },
+
+    /// Removes a recipient and re-encrypts the vault with a fresh data key
+    Remove {
+        /// Public key of the recipient (envbuddel-recipient-...)
+        recipient: String,
+    },
 }
 
-fn run(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
-    match &cli.command {
-        Commands::Init { folder } => {
-            let key = match Key::load_key(&cli.key, cli.keyfile.as_path()) {
-                Ok((key, _)) => key,
-                Err(_) => Key::generate(),
+/// Run the command. Returns the exit code, so the caller exits after the plaintext was wiped.
+fn run(cli: Cli) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let project = Project::find()?;
+    let env = environment(&cli, project.as_ref())?;
+    let mut status = ExitCode::SUCCESS;
+    let result: Result<(), Box<dyn std::error::Error>> = match &cli.command {
+        Commands::Init {
+            folder,
+            identity,
+            protect,
+            mode,
+            show_key,
+        } => {
+            let (key, key_source) = match Key::load_existing_key(
+                &env.key,
+                env.key_name(),
+                &env.keyfile,
+                env.permissions,
+            )? {
+                Some((key, key_source)) => (key, Some(key_source)),
+                None if *identity => (Key::generate_identity(), None),
+                None => (Key::generate(), None),
             };
 
-            info!("Generated new key 🔑");
-            info!("Please run this to provide the key as environment variable:\n");
-            info!("  $ export CI_SECRET=\"{}\"", key.to_printable());
-            info!("");
+            if key_source.is_some() {
+                info!("Using existing key 🔑");
+            } else {
+                info!("Generated new key 🔑");
+            }
+            print_key(
+                &env,
+                &key,
+                "Provide the key as environment variable",
+                *show_key,
+            );
+            if let Some(recipient) = key.recipient() {
+                info!("Your recipient (public key) is {}", recipient);
+            }
 
-            key.save_key(&cli.keyfile)?;
-            info!("💾 Key saved to {:?}", cli.keyfile);
+            match key_source {
+                Some(KeySource::ProtectedFile(_)) => {
+                    info!("🔐 Keeping passphrase-protected key in {:?}", env.keyfile);
+                }
+                _ if *protect => {
+                    let passphrase = protect::read_new_passphrase()?;
+                    key.save_protected_key(&env.keyfile, &passphrase)?;
+                    info!("🔐 Key saved to {:?}, protected by passphrase", env.keyfile);
+                }
+                _ => {
+                    key.save_key(&env.keyfile)?;
+                    info!("💾 Key saved to {:?}", env.keyfile);
+                }
+            }
 
             // add the secret files to the gitignore file
-            gitignore(vec![cli.keyfile.clone(), cli.env_conf.clone()])?;
+            gitignore(vec![env.keyfile.clone(), env.env_conf.clone()])?;
 
             if *folder {
-                if cli.env_conf.exists() && cli.env_conf.is_file() {
+                if env.env_conf.exists() && env.env_conf.is_file() {
                     Err("Environment already exists but it is not a folder!")?;
-                } else if !cli.env_conf.exists() {
-                    fs::create_dir_all(&cli.env_conf)?;
-                    info!("📂 Created environment folder at {:?}", cli.env_conf);
+                } else if !env.env_conf.exists() {
+                    fs::create_dir_all(&env.env_conf)?;
+                    fsutil::restrict(&env.env_conf)?;
+                    info!("📂 Created environment folder at {:?}", env.env_conf);
                 } else {
                     info!("📂 Environment already exists.");
                 }
             } else {
-                if cli.env_conf.exists() && cli.env_conf.is_dir() {
+                if env.env_conf.exists() && env.env_conf.is_dir() {
                     Err("Environment already exists but it is not a file!")?;
-                } else if !cli.env_conf.exists() {
-                    fs::write(&cli.env_conf, "")?;
-                    info!("📄 Created environment file at {:?}", cli.env_conf);
+                } else if !env.env_conf.exists() {
+                    fsutil::write_private(&env.env_conf, b"")?;
+                    info!("📄 Created environment file at {:?}", env.env_conf);
                 } else {
                     info!("📄 Environment already exists.");
                 }
             }
 
-            let pack = EnvironmentPack::from_path(&cli.env_conf)?;
-            let ciphertext = key.encrypt_base64(&pack)?;
+            let mode = mode.or(env.mode).unwrap_or(VaultMode::Blob);
+            let pack = EnvironmentPack::from_path(&env.env_conf, &env.pack)?;
+            let ciphertext = key.encrypt_vault(&pack, mode, None)?;
 
             // Write the ciphertext to output file
-            fs::write(&cli.vault, ciphertext)?;
+            fs::write(&env.vault, ciphertext)?;
+
+            info!("🔒 Vault successfully encrypted at {:?}", env.vault);
 
-            info!("🔒 Vault successfully encrypted at {:?}", cli.vault);
+            // Record the environment so a fresh clone works without flags
+            match &project {
+                Some(project) if project.contains(&env) => {
+                    debug!("Environment is configured in {:?}", project.path)
+                }
+                Some(project) => {
+                    project.add(&env, mode)?;
+                    info!("📝 Added environment {} to {:?}", env.label(), project.path);
+                }
+                None => {
+                    let path = config::create(&env, mode)?;
+                    info!("📝 Project configuration written to {:?}", path);
+                }
+            }
 
             Ok(())
         }
-        Commands::Info {} => {
-            if let Some(key) = cli.key.clone() {
-                match Key::load_key(&Some(key), Path::new("/dev/null")) {
+        Commands::Info { reveal } => {
+            if let Some(key) = env.key.clone() {
+                match Key::load_key(
+                    &Some(key),
+                    env.key_name(),
+                    Path::new("/dev/null"),
+                    env.permissions,
+                ) {
                     Ok((key, _)) => {
-                        info!("CI_SECRET=\"{}\"", key.to_base64());
-                    }
-                    Err(_) => {
-                        warn!("Failed to load private key from CI_SECRET=\"{}\". It needs to be 32 bytes encoded as base64!", cli.key.clone().unwrap_or("".to_string()));
+                        info!("Key in {}: {}", env.key_name(), describe_key(&key, *reveal));
                     }
+                    Err(err) => warn!("{}", err),
                 }
             }
 
-            match Key::load_key(&None, &cli.keyfile) {
-                Ok((key, _)) => {
+            // Permission problems are reported below, independent of the policy
+            let keyfile_key = Key::load_key(
+                &None,
+                env.key_name(),
+                &env.keyfile,
+                PermissionPolicy::Ignore,
+            );
+            match &keyfile_key {
+                Ok((key, key_source)) => {
                     info!(
-                        "Key contained in {:?}: \"{}\"",
-                        &cli.keyfile,
-                        key.to_base64()
+                        "Key contained in {:?}: {}",
+                        &env.keyfile,
+                        describe_key(key, *reveal)
                     );
+                    match key_source {
+                        KeySource::ProtectedFile(_) => info!("Keyfile is passphrase-protected."),
+                        _ => info!("Keyfile is stored in plaintext."),
+                    }
+                    if let Some(recipient) = key.recipient() {
+                        info!("Keyfile contains an identity with recipient {}", recipient);
+                    }
                 }
                 Err(err) => error!("{}", err),
             }
 
-            let (key, _) = Key::load_key(&cli.key, &cli.keyfile)?;
-            if cli.vault.exists() && cli.vault.is_file() {
+            for path in [&env.keyfile, &env.env_conf] {
+                if !path.exists() {
+                    continue;
+                }
+                for (path, problem) in fsutil::private_problems(path)? {
+                    match env.permissions {
+                        PermissionPolicy::Deny => {
+                            warn!("{:?} {} and will be refused.", path, problem)
+                        }
+                        _ => warn!("{:?} {}.", path, problem),
+                    }
+                }
+            }
+
+            // Reuse the keyfile key so a protected keyfile only asks once for the passphrase
+            let key = match &env.key {
+                Some(_) => {
+                    Key::load_key(&env.key, env.key_name(), &env.keyfile, env.permissions)?.0
+                }
+                None => keyfile_key?.0,
+            };
+            if env.vault.exists() && env.vault.is_file() {
                 info!("Vault files exist.");
-                let ciphertext = fs::read_to_string(cli.vault)?;
+                let ciphertext = fs::read_to_string(env.vault)?;
+                if VaultMode::detect(&ciphertext) == VaultMode::Values {
+                    info!("Vault encrypts every value individually.");
+                } else {
+                    match vault::inspect(&ciphertext)? {
+                        Some(header) => info!("Vault {}", header),
+                        None => {
+                            warn!("Vault has no header (legacy format). Run encrypt to upgrade it.")
+                        }
+                    }
+                }
+                info!("Fingerprint of the key in use: {}", key.fingerprint());
+                match vault::key_fingerprint(&ciphertext)? {
+                    Some(fingerprint) => {
+                        info!("Fingerprint of the vault key:  {}", fingerprint);
+                        if fingerprint == key.fingerprint() {
+                            info!("Key matches the vault.")
+                        } else {
+                            warn!("Key does not match the vault.")
+                        }
+                    }
+                    None => {
+                        let recipients = recipients::list(&ciphertext)?;
+                        match key.recipient() {
+                            Some(recipient) if recipients.contains(&recipient) => {
+                                info!("Identity is a recipient of the vault.")
+                            }
+                            Some(_) if !recipients.is_empty() => {
+                                warn!("Identity is not a recipient of the vault.")
+                            }
+                            _ => info!("Vault does not record its key. Run encrypt to add it."),
+                        }
+                    }
+                }
                 let _ = key.decrypt_base64(&ciphertext)?;
                 info!("Successfully decrypted vault file.");
             } else {
                 warn!("No vault file detected!");
             }
 
-            if cli.env_conf.exists() {
-                if cli.env_conf.is_file() {
+            if env.env_conf.exists() {
+                if env.env_conf.is_file() {
                     info!("Environment configuration file found.");
-                } else if cli.env_conf.is_dir() {
+                    let content = fs::read(&env.env_conf)?;
+                    match String::from_utf8(content)
+                        .map_err(|e| format!("Not valid UTF-8: {}", e))
+                        .and_then(|content| dotenv::parse(&content))
+                    {
+                        Ok(variables) => info!(
+                            "Environment configuration is valid and defines {} variable(s).",
+                            variables.len()
+                        ),
+                        Err(err) => warn!("Environment configuration is not valid: {}", err),
+                    }
+                } else if env.env_conf.is_dir() {
                     info!("Environment configuration folder found.");
+                    let paths = filepacker::packed_paths(&env.env_conf, &env.pack)?;
+                    info!("Files that are packed into the vault ({}):", paths.len());
+                    for path in paths {
+                        info!("  {}", path.display());
+                    }
                 } else {
                     warn!("Environment configuration is neither file nor folder!");
                 }
@@ -152,48 +473,631 @@ fn run(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
                 warn!("Environment configuration file/folder does not exists!");
             }
 
+            let names = match &project {
+                Some(project) => {
+                    info!("Project configuration {:?}", project.path);
+                    let mut names = environment::discover(&project.absolute_dir()?)?;
+                    if project.contains(&Environment::named(None)?) {
+                        names.push(None);
+                    }
+                    names.extend(project.config.environments.keys().cloned().map(Some));
+                    names.sort();
+                    names.dedup();
+                    names
+                }
+                None => {
+                    info!("No {} found, using the default paths.", config::CONFIG_FILE);
+                    environment::discover(Path::new("."))?
+                }
+            };
+            if !names.is_empty() {
+                info!("Environments:");
+            }
+            for name in names {
+                let mut other = Environment::named(name.as_deref())?;
+                if let Some(project) = &project {
+                    project.configure(&mut other);
+                }
+                other.key = other.key_from_var();
+                let marker = if other.name == env.name {
+                    // Permission problems of the current environment were reported above
+                    other.permissions = PermissionPolicy::Ignore;
+                    "*"
+                } else {
+                    " "
+                };
+                match environment_health(&other) {
+                    Ok(health) => info!("{} {}: {}", marker, other.label(), health),
+                    Err(problem) => warn!("{} {}: {}", marker, other.label(), problem),
+                }
+            }
+
             Ok(())
         }
-        Commands::Encrypt {} => {
-            let (key, key_source) = Key::load_key(&cli.key, cli.keyfile.as_path())?;
-            log_key_source(key_source);
+        Commands::Encrypt { mode } => {
+            let pack = EnvironmentPack::from_path(&env.env_conf, &env.pack)?;
+            if let EnvironmentPack::File(content) = &pack {
+                if let Err(err) = std::str::from_utf8(content)
+                    .map_err(|e| format!("Not valid UTF-8: {}", e))
+                    .and_then(dotenv::parse)
+                {
+                    warn!("{:?} is not a valid dotenv file: {}", env.env_conf, err);
+                }
+            }
+            let previous = fs::read_to_string(&env.vault).ok();
+            let mode = mode.unwrap_or(match &previous {
+                Some(previous) => VaultMode::detect(previous),
+                None => env.mode.unwrap_or(VaultMode::Blob),
+            });
 
-            let pack = EnvironmentPack::from_path(&cli.env_conf)?;
-            let ciphertext = key.encrypt_base64(&pack)?;
+            // Vaults with recipients only need their public keys
+            let recipients = vault_recipients(&env.vault)?;
+            let ciphertext = if recipients.is_empty() {
+                let (key, key_source) =
+                    Key::load_key(&env.key, env.key_name(), &env.keyfile, env.permissions)?;
+                log_key_source(key_source);
+                key.encrypt_vault(&pack, mode, previous.as_deref())?
+            } else if mode == VaultMode::Values {
+                Err("Per-value encryption is not available for vaults with recipients")?
+            } else {
+                info!("Encrypting to {} recipient(s)", recipients.len());
+                recipients::encrypt_base64(&pack, &recipients)?
+            };
 
             // Write the ciphertext to output file
-            fs::write(&cli.vault, ciphertext)?;
+            fs::write(&env.vault, ciphertext)?;
 
-            info!("Encrypted content successfully written to {:?}", cli.vault);
+            info!("Encrypted content successfully written to {:?}", env.vault);
             Ok(())
         }
         Commands::Decrypt {} => {
-            let (key, key_source) = Key::load_key(&cli.key, cli.keyfile.as_path())?;
+            let (pack, key_source) = load_pack(&env)?;
             log_key_source(key_source);
-            let ciphertext = fs::read_to_string(cli.vault)?;
-            let pack = key.decrypt_base64(&ciphertext)?;
-            pack.unpack(&cli.env_conf)?;
+            pack.unpack(&env.env_conf, &env.pack)?;
 
             info!(
                 "Decrypted content successfully written to {:?}",
-                cli.env_conf
+                env.env_conf
             );
             Ok(())
         }
+        Commands::Run { files, command } => {
+            let (pack, key_source) = load_pack(&env)?;
+            debug!("Key was loaded from {}", key_source);
+
+            let mut variables = Vec::new();
+            for content in dotenv_contents(&pack, files)? {
+                let content = std::str::from_utf8(&content)
+                    .map_err(|e| format!("Environment is not valid UTF-8: {}", e))?;
+                variables.extend(dotenv::parse(content)?);
+            }
+
+            let mut child = process::Command::new(&command[0]);
+            child
+                .args(&command[1..])
+                .envs(variables.iter().map(|(name, value)| (name, value.as_str())));
+            exec(child, &command[0])
+        }
+        Commands::Export { format, files } => {
+            let (pack, key_source) = load_pack(&env)?;
+            debug!("Key was loaded from {}", key_source);
+
+            let mut variables = Vec::new();
+            for content in dotenv_contents(&pack, files)? {
+                let content = std::str::from_utf8(&content)
+                    .map_err(|e| format!("Environment is not valid UTF-8: {}", e))?;
+                variables.extend(dotenv::parse(content)?);
+            }
+
+            if *format == export::Format::Gitlab {
+                for warning in export::gitlab_warnings(&variables) {
+                    warn!("{}", warning);
+                }
+            }
+            let github_env = std::env::var_os(export::GITHUB_ENV);
+            export::write(
+                &variables,
+                *format,
+                github_env.as_deref().map(Path::new),
+                &mut io::stdout(),
+            )?;
+            Ok(())
+        }
+        Commands::List { format } => {
+            let (pack, key_source) = load_pack(&env)?;
+            debug!("Key was loaded from {}", key_source);
+
+            print!("{}", list::Listing::of(&pack)?.render(*format)?);
+            Ok(())
+        }
+        Commands::Cat { paths } => {
+            let (pack, key_source) = load_pack(&env)?;
+            debug!("Key was loaded from {}", key_source);
+
+            let mut stdout = io::stdout().lock();
+            pack.select_files(paths, |path, _, reader| {
+                io::copy(reader, &mut stdout)
+                    .map(|_| ())
+                    .map_err(|e| format!("Failed to print {:?}: {}", path, e))
+            })?;
+            Ok(())
+        }
+        Commands::Extract { paths, to } => {
+            let (pack, key_source) = load_pack(&env)?;
+            debug!("Key was loaded from {}", key_source);
+
+            for path in pack.extract(paths, to, &env.pack)? {
+                info!("📄 Extracted {:?}", path);
+            }
+            Ok(())
+        }
+        Commands::Diff { show_values } => {
+            let (pack, key_source) = load_pack(&env)?;
+            debug!("Key was loaded from {}", key_source);
+
+            let working = EnvironmentPack::from_path(&env.env_conf, &env.pack)?;
+            let differences = diff::compare(&pack, &working)?;
+            if differences.is_empty() {
+                info!("{:?} is up to date with {:?}", env.vault, env.env_conf);
+            } else {
+                print!("{}", diff::render(&differences, *show_values).as_str());
+                status = ExitCode::from(1);
+            }
+            Ok(())
+        }
+        Commands::Rotate {
+            old_key,
+            new_key,
+            show_key,
+        } => {
+            let (old_key, key_source) = match old_key {
+                Some(old_key) => (Key::from_printable(old_key)?, None),
+                None => {
+                    let (key, key_source) =
+                        Key::load_key(&env.key, env.key_name(), &env.keyfile, env.permissions)?;
+                    (key, Some(key_source))
+                }
+            };
+            if let Some(key_source) = &key_source {
+                log_key_source(key_source.clone());
+            }
+
+            let ciphertext = fs::read_to_string(&env.vault)?;
+            if !recipients::list(&ciphertext)?.is_empty() {
+                Err("Vault is encrypted to recipients. Use `recipients remove` to revoke access, it re-encrypts the vault with a fresh data key.")?;
+            }
+            let pack = old_key.decrypt_base64(&ciphertext)?;
+
+            let new_key = match new_key {
+                Some(new_key) => Key::from_printable(new_key)?,
+                None => Key::generate(),
+            };
+            if new_key.as_bytes() == old_key.as_bytes() {
+                Err("The new key is identical to the current key")?;
+            }
+
+            // Make sure the new vault can be read before anything is replaced
+            let new_vault = new_key.encrypt_vault(&pack, VaultMode::detect(&ciphertext), None)?;
+            new_key.decrypt_base64(&new_vault)?;
+
+            // Update the keyfile only if the current key was loaded from it, keeping its passphrase protection
+            let keyfile_content = match &key_source {
+                Some(KeySource::File(_) | KeySource::ProtectedFile(_)) if !env.keyfile.exists() => {
+                    Err(format!("Keyfile {:?} disappeared", env.keyfile))?
+                }
+                Some(KeySource::ProtectedFile(_)) => {
+                    Some(new_key.to_keyfile(Some(&protect::read_new_passphrase()?))?)
+                }
+                Some(KeySource::File(_)) => Some(new_key.to_keyfile(None)?),
+                _ => None,
+            };
+
+            let staged_vault = fsutil::stage(&env.vault, new_vault.as_bytes())?;
+            let staged_keyfile = match &keyfile_content {
+                Some(content) => Some(fsutil::stage(&env.keyfile, content.as_bytes())?),
+                None => None,
+            };
+
+            fsutil::commit(staged_vault, &env.vault)?;
+            if let Some(staged_keyfile) = staged_keyfile {
+                if let Err(err) = fsutil::commit(staged_keyfile, &env.keyfile) {
+                    // Put the old vault back so it still matches the old keyfile
+                    fsutil::write_atomic(&env.vault, ciphertext.as_bytes()).map_err(|restore_err| {
+                        format!(
+                            "{}. Restoring the old vault failed as well: {}. The vault is now encrypted with the new key \"{}\"",
+                            err, restore_err, new_key.to_printable().as_str()
+                        )
+                    })?;
+                    Err(format!(
+                        "{}. The old vault was restored, nothing was rotated.",
+                        err
+                    ))?;
+                }
+                info!("💾 New key saved to {:?}", env.keyfile);
+            }
+            info!("🔒 Vault {:?} re-encrypted with the new key", env.vault);
+
+            // Without a keyfile the new key exists nowhere else
+            print_key(
+                &env,
+                &new_key,
+                "🔑 Please update the key in your CI/CD settings",
+                *show_key || keyfile_content.is_none(),
+            );
+            Ok(())
+        }
+        Commands::Get { name } => {
+            let (file, name) = parse_address(name);
+            let (pack, key_source) = load_pack(&env)?;
+            debug!("Key was loaded from {}", key_source);
+
+            let content = dotenv_content(&pack, file)?;
+            let value = dotenv::parse(&content)?
+                .into_iter()
+                .rev()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value)
+                .ok_or_else(|| format!("Variable {} not found", name))?;
+
+            println!("{}", value.as_str());
+            Ok(())
+        }
+        Commands::Set { assignment } => {
+            let (address, value) = match assignment.split_once('=') {
+                Some((address, value)) => (address, Zeroizing::new(value.to_string())),
+                None => {
+                    let mut value = Zeroizing::new(String::new());
+                    io::stdin().read_to_string(&mut value)?;
+                    // A single trailing line break, also from files with Windows line endings
+                    let value = value
+                        .strip_suffix("\r\n")
+                        .or_else(|| value.strip_suffix('\n'))
+                        .unwrap_or(&value);
+                    (assignment.as_str(), Zeroizing::new(value.to_string()))
+                }
+            };
+            let (file, name) = parse_address(address);
+
+            let (key, key_source, ciphertext) = open_vault(&env)?;
+            log_key_source(key_source);
+            let mut pack = key.decrypt_base64(&ciphertext)?;
+
+            let content = dotenv::set(&dotenv_content(&pack, file)?, name, &value)?;
+            pack.write_file(file.unwrap_or_default(), content.as_bytes())?;
+            save_vault(&env, &key, &pack, &ciphertext)?;
+
+            info!("Set {} in {:?}", name, env.vault);
+            Ok(())
+        }
+        Commands::Edit {} => {
+            let (key, key_source, ciphertext) = open_vault(&env)?;
+            log_key_source(key_source);
+            let pack = key.decrypt_base64(&ciphertext)?;
+
+            let name = env
+                .env_conf
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or(".env");
+            let workspace = edit::Workspace::new(&pack, name, &env.pack)?;
+            loop {
+                let edited = match workspace.edit(&pack)? {
+                    Some(edited) => edited,
+                    None => {
+                        info!("No changes, vault left untouched.");
+                        return Ok(ExitCode::SUCCESS);
+                    }
+                };
+                match save_vault(&env, &key, &edited, &ciphertext) {
+                    Ok(()) => {
+                        info!("🔒 Vault {:?} updated", env.vault);
+                        return Ok(ExitCode::SUCCESS);
+                    }
+                    Err(err) => {
+                        error!("{}", err);
+                        if !edit::ask_retry()? {
+                            Err("Changes discarded")?;
+                        }
+                    }
+                }
+            }
+        }
+        Commands::Key {
+            command: KeyCommands::Identity {},
+        } => {
+            if env.keyfile.exists() {
+                Err(format!(
+                    "Keyfile {:?} already exists. Choose another path with --keyfile",
+                    env.keyfile
+                ))?;
+            }
+
+            let identity = Key::generate_identity();
+            identity.save_key(&env.keyfile)?;
+            info!("💾 Identity saved to {:?}", env.keyfile);
+            if let Some(recipient) = identity.recipient() {
+                info!("Share your recipient (public key) to get access to vaults:\n");
+                info!("  {}", recipient);
+            }
+            Ok(())
+        }
+        Commands::Key {
+            command: KeyCommands::Protect {},
+        } => {
+            let (key, key_source) =
+                Key::load_key(&None, env.key_name(), &env.keyfile, env.permissions)?;
+            if let KeySource::ProtectedFile(_) = key_source {
+                Err(format!(
+                    "Keyfile {:?} is already passphrase-protected",
+                    env.keyfile
+                ))?;
+            }
+
+            let passphrase = protect::read_new_passphrase()?;
+            key.save_protected_key(&env.keyfile, &passphrase)?;
+            info!(
+                "🔐 Keyfile {:?} is now protected by passphrase",
+                env.keyfile
+            );
+            Ok(())
+        }
+        Commands::Recipients {
+            command: RecipientsCommands::List {},
+        } => {
+            let recipients = vault_recipients(&env.vault)?;
+            if recipients.is_empty() {
+                info!("Vault is encrypted with a symmetric key and has no recipients.");
+            }
+            for recipient in recipients {
+                info!("{}", recipient);
+            }
+            Ok(())
+        }
+        Commands::Recipients {
+            command: RecipientsCommands::Add { recipient },
+        } => {
+            let recipient = Recipient::from_printable(recipient)?;
+            let mut recipients = vault_recipients(&env.vault)?;
+            if recipients.contains(&recipient) {
+                Err(format!("{} is already a recipient of the vault", recipient))?;
+            }
+
+            let (pack, key_source) = load_pack(&env)?;
+            log_key_source(key_source);
+            if recipients.is_empty() {
+                warn!("Vault was encrypted with a symmetric key. From now on only its recipients can decrypt it.");
+            }
+
+            recipients.push(recipient.clone());
+            fs::write(&env.vault, recipients::encrypt_base64(&pack, &recipients)?)?;
+            info!("Added {} to {:?}", recipient, env.vault);
+            Ok(())
+        }
+        Commands::Recipients {
+            command: RecipientsCommands::Remove { recipient },
+        } => {
+            let recipient = Recipient::from_printable(recipient)?;
+            let mut recipients = vault_recipients(&env.vault)?;
+            if !recipients.contains(&recipient) {
+                Err(format!("{} is not a recipient of the vault", recipient))?;
+            }
+            recipients.retain(|r| r != &recipient);
+            if recipients.is_empty() {
+                Err("Refusing to remove the last recipient of the vault")?;
+            }
+
+            let (pack, key_source) = load_pack(&env)?;
+            log_key_source(key_source);
+
+            fs::write(&env.vault, recipients::encrypt_base64(&pack, &recipients)?)?;
+            info!("Removed {} from {:?}", recipient, env.vault);
+            Ok(())
+        }
+    };
+    result.map(|_| status)
+}
+
+/// Resolve the environment selected with --env from the project configuration,
+/// overridden by the explicit path and key options
+fn environment(cli: &Cli, project: Option<&Project>) -> Result<Environment, String> {
+    let mut environment = Environment::named(cli.env.as_deref())?;
+    if let Some(project) = project {
+        project.configure(&mut environment);
+    }
+    if let Some(keyfile) = &cli.keyfile {
+        environment.keyfile = keyfile.clone();
+    }
+    if let Some(env_conf) = &cli.env_conf {
+        environment.env_conf = env_conf.clone();
+    }
+    if let Some(vault) = &cli.vault {
+        environment.vault = vault.clone();
     }
+    if let Some(key_var) = &cli.key_var {
+        environment.key_var = key_var.clone();
+    }
+    environment.pack.exclude.extend(cli.exclude.iter().cloned());
+    environment.key_from_arg = cli.key.is_some();
+    environment.key = cli.key.clone().or_else(|| environment.key_from_var());
+    Ok(environment)
 }
 
-fn log_key_source(key_source: KeySource) {
-    match key_source {
-        KeySource::File(key_file) => {
-            info!("Key was loaded from {:?}", key_file)
+/// Check whether the vault of the environment decrypts, without asking for a passphrase
+fn environment_health(env: &Environment) -> Result<String, String> {
+    if !env.vault.is_file() {
+        return Err(format!("no vault {:?}", env.vault));
+    }
+    let source = match &env.key {
+        Some(_) => env.key_name().to_string(),
+        None => {
+            let content = fs::read_to_string(&env.keyfile)
+                .map_err(|_| format!("no key in {} or {:?}", env.key_var, env.keyfile))?;
+            if content.lines().any(|l| protect::is_protected(l.trim())) {
+                return Ok(format!(
+                    "{:?} is passphrase-protected, decryption not checked",
+                    env.keyfile
+                ));
+            }
+            format!("{:?}", env.keyfile)
         }
-        KeySource::Env => {
-            info!("Key was loaded from CI_SECRET")
+    };
+    let (key, _, ciphertext) = open_vault(env)?;
+    key.decrypt_base64(&ciphertext).map_err(|err| {
+        format!(
+            "{:?} does not decrypt with the key from {}: {}",
+            env.vault, source, err
+        )
+    })?;
+    Ok(format!(
+        "{:?} decrypts with the key from {}",
+        env.vault, source
+    ))
+}
+
+/// Recipients of an existing vault. Missing vaults and symmetric vaults have none.
+fn vault_recipients(vault: &Path) -> Result<Vec<Recipient>, String> {
+    if !vault.exists() {
+        return Ok(Vec::new());
+    }
+    let ciphertext = fs::read_to_string(vault)
+        .map_err(|e| format!("Failed to read vault {:?}: {}", vault, e))?;
+    recipients::list(&ciphertext)
+}
+
+/// Load the key and the vault content
+fn open_vault(env: &Environment) -> Result<(Key, KeySource, String), String> {
+    let (key, key_source) = Key::load_key(&env.key, env.key_name(), &env.keyfile, env.permissions)?;
+    let ciphertext = fs::read_to_string(&env.vault)
+        .map_err(|e| format!("Failed to read vault {:?}: {}", env.vault, e))?;
+    Ok((key, key_source, ciphertext))
+}
+
+/// Load the key and decrypt the vault in memory
+fn load_pack(env: &Environment) -> Result<(EnvironmentPack, KeySource), String> {
+    let (key, key_source, ciphertext) = open_vault(env)?;
+    Ok((key.decrypt_base64(&ciphertext)?, key_source))
+}
+
+/// Re-encrypt a modified pack the same way as the `previous` vault content, keeping mode and recipients
+fn save_vault(
+    env: &Environment,
+    key: &Key,
+    pack: &EnvironmentPack,
+    previous: &str,
+) -> Result<(), String> {
+    let recipients = recipients::list(previous)?;
+    let ciphertext = if recipients.is_empty() {
+        key.encrypt_vault(pack, VaultMode::detect(previous), Some(previous))?
+    } else {
+        recipients::encrypt_base64(pack, &recipients)?
+    };
+    fsutil::write_atomic(&env.vault, ciphertext.as_bytes())
+}
+
+/// Split `file:NAME` into the file inside a folder vault and the variable name
+fn parse_address(address: &str) -> (Option<&str>, &str) {
+    match address.rsplit_once(':') {
+        Some((file, name)) => (Some(file), name),
+        None => (None, address),
+    }
+}
+
+/// Dotenv content of a file pack, or of the named file inside a folder pack
+fn dotenv_content(pack: &EnvironmentPack, file: Option<&str>) -> Result<Zeroizing<String>, String> {
+    let files: Vec<String> = file.map(str::to_string).into_iter().collect();
+    let content = dotenv_contents(pack, &files)?.remove(0);
+    std::str::from_utf8(&content)
+        .map(|content| Zeroizing::new(content.to_string()))
+        .map_err(|e| format!("Environment is not valid UTF-8: {}", e))
+}
+
+/// Select the dotenv content of the pack. Folder packs need the files inside the vault to be named.
+fn dotenv_contents(
+    pack: &EnvironmentPack,
+    files: &[String],
+) -> Result<Vec<Zeroizing<Vec<u8>>>, String> {
+    match pack {
+        EnvironmentPack::File(data) => {
+            if !files.is_empty() {
+                warn!("Vault contains a single file, ignoring --file");
+            }
+            Ok(vec![Zeroizing::new(data.clone())])
+        }
+        EnvironmentPack::Folder(_) => {
+            if files.is_empty() {
+                return Err(
+                    "Vault contains a folder. Select the dotenv file(s) to load with --file"
+                        .to_string(),
+                );
+            }
+            files.iter().map(|file| pack.read_file(file)).collect()
         }
     }
 }
 
+/// Replace the current process with the command so signals and exit code are passed through
+#[cfg(unix)]
+fn exec(mut command: process::Command, program: &str) -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::process::CommandExt;
+
+    let err = command.exec();
+    Err(format!("Failed to execute {:?}: {}", program, err))?
+}
+
+/// Run the command and exit with its exit code
+#[cfg(not(unix))]
+fn exec(mut command: process::Command, program: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let status = command
+        .status()
+        .map_err(|e| format!("Failed to execute {:?}: {}", program, e))?;
+    process::exit(status.code().unwrap_or(1));
+}
+
+/// The printable key with `reveal`, otherwise its fingerprint
+fn describe_key(key: &Key, reveal: bool) -> String {
+    if reveal {
+        format!("\"{}\"", key.to_printable().as_str())
+    } else {
+        format!(
+            "fingerprint {} (use --reveal to print the key)",
+            key.fingerprint()
+        )
+    }
+}
+
+/// Print the export line for the key. Unless `show` is set the key is only printed when the log output
+/// on stderr is a terminal, so it does not end up in CI logs or redirected output.
+fn print_key(env: &Environment, key: &Key, heading: &str, show: bool) {
+    for line in key_lines(env, key, heading, show || io::stderr().is_terminal()) {
+        info!("{}", line.as_str());
+    }
+}
+
+/// Lines printed by `print_key`, with only the fingerprint unless `show` is set
+fn key_lines(env: &Environment, key: &Key, heading: &str, show: bool) -> Vec<Zeroizing<String>> {
+    if show {
+        vec![
+            Zeroizing::new(format!("{}:\n", heading)),
+            Zeroizing::new(format!(
+                "  $ export {}=\"{}\"",
+                env.key_var,
+                key.to_printable().as_str()
+            )),
+            Zeroizing::new(String::new()),
+        ]
+    } else {
+        vec![Zeroizing::new(format!(
+            "{} (fingerprint {}). The key is not printed because the output is not a terminal, use --show-key to print it.",
+            heading,
+            key.fingerprint()
+        ))]
+    }
+}
+
+fn log_key_source(key_source: KeySource) {
+    info!("Key was loaded from {}", key_source)
+}
+
 fn init_logger(verbosity: u8) {
     use env_logger::{Builder, Target};
     use std::io::Write;
@@ -202,12 +1106,13 @@ fn init_logger(verbosity: u8) {
         .format(|buf, record| {
             let msg = format!("{}", record.args());
             match record.level() {
-                log::Level::Warn => writeln!(buf, "\x1b[33m[WARN] {}\x1b[0m", msg), // red
+                log::Level::Warn => writeln!(buf, "\x1b[33m[WARN] {}\x1b[0m", msg), // yellow
                 log::Level::Error => writeln!(buf, "\x1b[91m[ERROR] {}\x1b[0m", msg), // bright red
                 _ => writeln!(buf, "{}", msg),                                      // default
             }
         })
-        .target(Target::Stdout)
+        // All messages go to stderr so stdout only carries the output of export, get, list etc.
+        .target(Target::Stderr)
         .filter_level(match verbosity {
             0 => log::LevelFilter::Info,  // always show info & higher
             1 => log::LevelFilter::Debug, // debug + info + warn + error
@@ -216,11 +1121,41 @@ fn init_logger(verbosity: u8) {
         .init();
 }
 
-fn main() {
+fn main() -> ExitCode {
     let cli = Cli::parse();
     init_logger(cli.verbose);
-    if let Err(err) = run(cli) {
-        error!("{}", err);
-        std::process::exit(1);
+    secret::disable_core_dumps();
+    // `diff` exits with 1 when the vault differs, so errors need another code
+    let code = if matches!(cli.command, Commands::Diff { .. }) {
+        2
+    } else {
+        1
+    };
+    match run(cli) {
+        Ok(status) => status,
+        Err(err) => {
+            error!("{}", err);
+            ExitCode::from(code)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test that the key is left out of the log unless it is shown, e.g. when stderr is redirected
+    #[test]
+    fn test_key_lines() {
+        let env = Environment::named(None).unwrap();
+        let key = Key::generate();
+        let printable = key.to_printable();
+
+        let hidden = key_lines(&env, &key, "Key", false);
+        assert!(hidden.iter().all(|line| !line.contains(printable.as_str())));
+        assert!(hidden[0].contains(&key.fingerprint()));
+
+        let shown = key_lines(&env, &key, "Key", true);
+        assert!(shown.iter().any(|line| line.contains(printable.as_str())));
     }
 }
@@ -0,0 +1,227 @@
+use crate::crypto::Key;
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::Engine;
+use rand::RngCore;
+use std::env;
+use zeroize::Zeroizing;
+
+/// Prefix of a passphrase-protected key in a keyfile
+pub const PROTECTED_PREFIX: &str = "ENVBUDDEL-PROTECTED-KEY-v1";
+
+/// Environment variable to supply the passphrase non-interactively
+pub const PASSPHRASE_ENV: &str = "ENVBUDDEL_PASSPHRASE";
+
+/// Argon2id cost parameters stored alongside the protected key
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct KdfParams {
+    /// Memory in KiB
+    pub m_cost: u32,
+    /// Number of iterations
+    pub t_cost: u32,
+    /// Degree of parallelism
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KdfParams {
+    fn to_printable(self) -> String {
+        format!("m={},t={},p={}", self.m_cost, self.t_cost, self.p_cost)
+    }
+
+    fn from_printable(encoded: &str) -> Result<Self, String> {
+        let mut params = KdfParams::default();
+        for part in encoded.split(',') {
+            let (name, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid Argon2 parameter {:?}", part))?;
+            let value = value
+                .parse()
+                .map_err(|e| format!("Invalid Argon2 parameter {:?}: {}", part, e))?;
+            match name {
+                "m" => params.m_cost = value,
+                "t" => params.t_cost = value,
+                "p" => params.p_cost = value,
+                _ => return Err(format!("Unknown Argon2 parameter {:?}", name)),
+            }
+        }
+        Ok(params)
+    }
+
+    /// Derive the key that encrypts the protected key from the passphrase
+    fn derive(&self, passphrase: &str, salt: &[u8]) -> Result<Key, String> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32))
+            .map_err(|e| format!("Invalid Argon2 parameters: {}", e))?;
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), salt, &mut *bytes)
+            .map_err(|e| format!("Failed to derive key from passphrase: {}", e))?;
+        Key::from_bytes(&*bytes)
+    }
+}
+
+/// Whether a keyfile line contains a passphrase-protected key
+pub fn is_protected(line: &str) -> bool {
+    line.starts_with(PROTECTED_PREFIX)
+}
+
+/// Encrypt the key under the passphrase.
+/// Returns a single line `ENVBUDDEL-PROTECTED-KEY-v1:argon2id:<params>:<salt>:<ciphertext>`.
+pub fn protect(key: &Key, passphrase: &str, params: KdfParams) -> Result<String, String> {
+    let mut salt = [0u8; 16];
+    rand::rng().fill_bytes(&mut salt);
+
+    let b64 = base64::engine::general_purpose::STANDARD_NO_PAD;
+    let prefix = format!(
+        "{}:argon2id:{}:{}",
+        PROTECTED_PREFIX,
+        params.to_printable(),
+        b64.encode(salt)
+    );
+
+    // The parameters and salt are authenticated so they cannot be downgraded
+    let ciphertext = params
+        .derive(passphrase, &salt)?
+        .seal(key.to_printable().as_bytes(), prefix.as_bytes())?;
+
+    Ok(format!("{}:{}", prefix, b64.encode(ciphertext)))
+}
+
+/// Decrypt a protected key line with the passphrase
+pub fn unprotect(line: &str, passphrase: &str) -> Result<Key, String> {
+    let parts: Vec<&str> = line.trim().split(':').collect();
+    if parts.len() != 5 || parts[0] != PROTECTED_PREFIX {
+        return Err("Malformed passphrase-protected key".to_string());
+    }
+    if parts[1] != "argon2id" {
+        return Err(format!("Unsupported key derivation {:?}", parts[1]));
+    }
+
+    let b64 = base64::engine::general_purpose::STANDARD_NO_PAD;
+    let params = KdfParams::from_printable(parts[2])?;
+    let salt = b64
+        .decode(parts[3])
+        .map_err(|e| format!("Failed to decode salt: {}", e))?;
+    let ciphertext = b64
+        .decode(parts[4])
+        .map_err(|e| format!("Failed to decode protected key: {}", e))?;
+
+    let prefix = parts[..4].join(":");
+    let printable = params
+        .derive(passphrase, &salt)?
+        .open(&ciphertext, prefix.as_bytes())
+        .map_err(|_| "Wrong passphrase or corrupted keyfile".to_string())?;
+    let printable = std::str::from_utf8(&printable)
+        .map_err(|e| format!("Protected key is not valid UTF-8: {}", e))?;
+    Key::from_printable(printable)
+}
+
+/// Read the passphrase from ENVBUDDEL_PASSPHRASE or prompt for it on the terminal
+pub fn read_passphrase(prompt: &str) -> Result<Zeroizing<String>, String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+        return Ok(Zeroizing::new(passphrase));
+    }
+    rpassword::prompt_password(prompt)
+        .map(Zeroizing::new)
+        .map_err(|e| {
+            format!(
+                "Failed to read passphrase (set {} for non-interactive use): {}",
+                PASSPHRASE_ENV, e
+            )
+        })
+}
+
+/// Ask for a new passphrase twice, or take it from ENVBUDDEL_PASSPHRASE
+pub fn read_new_passphrase() -> Result<Zeroizing<String>, String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+        return non_empty(Zeroizing::new(passphrase));
+    }
+    let passphrase = non_empty(read_passphrase("New passphrase: ")?)?;
+    if read_passphrase("Repeat passphrase: ")? != passphrase {
+        return Err("Passphrases do not match".to_string());
+    }
+    Ok(passphrase)
+}
+
+/// Reject an empty new passphrase, wherever it was read from
+fn non_empty(passphrase: Zeroizing<String>) -> Result<Zeroizing<String>, String> {
+    if passphrase.is_empty() {
+        return Err("Passphrase must not be empty".to_string());
+    }
+    Ok(passphrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cheap parameters to keep the tests fast
+    const TEST_PARAMS: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    // Test protect/unprotect roundtrip
+    #[test]
+    fn test_protect_roundtrip() {
+        let key = Key::generate();
+        let line = protect(&key, "correct horse", TEST_PARAMS).unwrap();
+        assert!(is_protected(&line));
+        assert!(line.contains(":argon2id:m=64,t=1,p=1:"));
+
+        let loaded = unprotect(&line, "correct horse").unwrap();
+        assert_eq!(loaded.as_bytes(), key.as_bytes());
+    }
+
+    // Test that identities keep their kind
+    #[test]
+    fn test_protect_identity() {
+        let identity = Key::generate_identity();
+        let line = protect(&identity, "pw", TEST_PARAMS).unwrap();
+        let loaded = unprotect(&line, "pw").unwrap();
+        assert_eq!(loaded.recipient(), identity.recipient());
+    }
+
+    // Test that a wrong passphrase is rejected
+    #[test]
+    fn test_unprotect_wrong_passphrase() {
+        let line = protect(&Key::generate(), "right", TEST_PARAMS).unwrap();
+        let result = unprotect(&line, "wrong");
+        assert!(result.is_err_and(|err| err.contains("Wrong passphrase")));
+    }
+
+    // Test that tampering with the stored parameters is detected
+    #[test]
+    fn test_unprotect_tampered_params() {
+        let line = protect(&Key::generate(), "pw", TEST_PARAMS).unwrap();
+        let tampered = line.replace("t=1", "t=2");
+        assert!(unprotect(&tampered, "pw").is_err());
+    }
+
+    // Test that an empty new passphrase is rejected
+    #[test]
+    fn test_non_empty() {
+        assert!(non_empty(Zeroizing::new(String::new())).is_err());
+        assert_eq!(*non_empty(Zeroizing::new("pw".to_string())).unwrap(), "pw");
+    }
+
+    // Test parsing of Argon2 parameters
+    #[test]
+    fn test_params_printable_roundtrip() {
+        let params = KdfParams::default();
+        assert_eq!(
+            KdfParams::from_printable(&params.to_printable()).unwrap(),
+            params
+        );
+        assert!(KdfParams::from_printable("x=1").is_err());
+    }
+}
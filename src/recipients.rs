@@ -0,0 +1,230 @@
+use crate::crypto::{Key, BASE62};
+use crate::filepacker::EnvironmentPack;
+use crate::values;
+use crate::vault::{self, Kdf, Stanza, VaultHeader, WRAPPED_KEY_LEN};
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::Sha256;
+use std::fmt;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+/// Prefix of a printable recipient public key
+pub const RECIPIENT_PREFIX: &str = "envbuddel-recipient-";
+
+/// HKDF info string binding wrapped keys to this scheme
+const WRAP_INFO: &[u8] = b"envbuddel-x25519-v1";
+
+/// X25519 public key a vault can be encrypted to
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Recipient {
+    bytes: [u8; 32],
+}
+
+impl Recipient {
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn from_printable(encoded: &str) -> Result<Self, String> {
+        let encoded = encoded.trim();
+        let body = encoded.strip_prefix(RECIPIENT_PREFIX).ok_or_else(|| {
+            format!(
+                "Invalid recipient {:?}: expected it to start with {:?}",
+                encoded, RECIPIENT_PREFIX
+            )
+        })?;
+        let bytes = base_x::decode(BASE62, body)
+            .map_err(|e| format!("Failed to decode recipient {:?}: {}", encoded, e))?;
+        let bytes: [u8; 32] = bytes.try_into().map_err(|bytes: Vec<u8>| {
+            format!(
+                "Invalid recipient length: expected 32 bytes, got {}",
+                bytes.len()
+            )
+        })?;
+        Ok(Self { bytes })
+    }
+
+    pub fn to_printable(&self) -> String {
+        format!(
+            "{}{}",
+            RECIPIENT_PREFIX,
+            base_x::encode(BASE62, &self.bytes)
+        )
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_printable())
+    }
+}
+
+/// Derive the public recipient of an X25519 secret
+pub fn public_key(secret: &[u8; 32]) -> Recipient {
+    let secret = StaticSecret::from(*secret);
+    Recipient::from_bytes(PublicKey::from(&secret).to_bytes())
+}
+
+/// Derive the key that wraps the data key from the X25519 shared secret
+fn wrapping_key(shared: &[u8; 32], ephemeral: &[u8; 32], recipient: &[u8; 32]) -> Key {
+    let mut salt = Vec::with_capacity(64);
+    salt.extend_from_slice(ephemeral);
+    salt.extend_from_slice(recipient);
+
+    let mut okm = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(WRAP_INFO, &mut *okm)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    Key::from_bytes(&*okm).expect("HKDF output has key length")
+}
+
+/// Wrap the data key for a single recipient using an ephemeral key agreement
+pub fn wrap(data_key: &Key, recipient: &Recipient) -> Result<Stanza, String> {
+    let mut ephemeral_secret = Zeroizing::new([0u8; 32]);
+    rand::rng().fill_bytes(&mut *ephemeral_secret);
+    let ephemeral_secret = StaticSecret::from(*ephemeral_secret);
+    let ephemeral = PublicKey::from(&ephemeral_secret).to_bytes();
+
+    let shared = ephemeral_secret.diffie_hellman(&PublicKey::from(recipient.bytes));
+    let wrapped = wrapping_key(shared.as_bytes(), &ephemeral, &recipient.bytes)
+        .seal(data_key.as_bytes(), &[])?;
+
+    let mut wrapped_key = [0u8; WRAPPED_KEY_LEN];
+    wrapped_key.copy_from_slice(&wrapped);
+    Ok(Stanza {
+        recipient: recipient.bytes,
+        ephemeral,
+        wrapped_key,
+    })
+}
+
+/// Find the stanza of the identity and unwrap the data key
+pub fn unwrap(stanzas: &[Stanza], identity: &[u8; 32]) -> Result<Key, String> {
+    let recipient = public_key(identity);
+    let stanza = stanzas
+        .iter()
+        .find(|stanza| stanza.recipient == recipient.bytes)
+        .ok_or_else(|| {
+            format!(
+                "Your identity {} is not a recipient of this vault",
+                recipient
+            )
+        })?;
+
+    let shared = StaticSecret::from(*identity).diffie_hellman(&PublicKey::from(stanza.ephemeral));
+    let data_key = wrapping_key(shared.as_bytes(), &stanza.ephemeral, &stanza.recipient)
+        .open(&stanza.wrapped_key, &[])
+        .map_err(|_| "Failed to unwrap the data key of the vault: corrupted stanza".to_string())?;
+    Key::from_bytes(&data_key)
+}
+
+/// Recipients listed in the header of an armored vault.
+/// Vaults encrypted with a symmetric key, including per-value vaults, have no recipients.
+pub fn list(armored: &str) -> Result<Vec<Recipient>, String> {
+    if values::is_values_vault(armored) {
+        return Ok(Vec::new());
+    }
+    Ok(match vault::inspect(armored)? {
+        Some(header) => header
+            .stanzas
+            .iter()
+            .map(|stanza| Recipient::from_bytes(stanza.recipient))
+            .collect(),
+        None => Vec::new(),
+    })
+}
+
+/// Encrypt the pack with a fresh data key that is wrapped for every recipient
+pub fn encrypt_base64(pack: &EnvironmentPack, recipients: &[Recipient]) -> Result<String, String> {
+    if recipients.is_empty() {
+        return Err("A vault needs at least one recipient".to_string());
+    }
+
+    let data_key = Key::generate();
+    let header = VaultHeader {
+        kdf: Kdf::X25519,
+        stanzas: recipients
+            .iter()
+            .map(|recipient| wrap(&data_key, recipient))
+            .collect::<Result<_, _>>()?,
+        ..VaultHeader::default()
+    };
+
+    let mut bytes = header.to_bytes();
+    let ciphertext = data_key.seal(pack.to_bytes()?.as_slice(), &bytes)?;
+    bytes.extend_from_slice(&ciphertext);
+    Ok(vault::encode_armor(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test printable recipient roundtrip
+    #[test]
+    fn test_recipient_printable_roundtrip() {
+        let identity = Key::generate_identity();
+        let recipient = identity.recipient().unwrap();
+        let printable = recipient.to_printable();
+        assert!(printable.starts_with(RECIPIENT_PREFIX));
+        assert_eq!(Recipient::from_printable(&printable).unwrap(), recipient);
+    }
+
+    // Test that malformed recipients are rejected
+    #[test]
+    fn test_recipient_invalid() {
+        assert!(Recipient::from_printable("abc").is_err());
+        assert!(Recipient::from_printable(&format!("{}AAAA", RECIPIENT_PREFIX)).is_err());
+    }
+
+    // Test wrapping and unwrapping the data key
+    #[test]
+    fn test_wrap_unwrap() {
+        let identity = Key::generate_identity();
+        let other = Key::generate_identity();
+        let data_key = Key::generate();
+
+        let stanza = wrap(&data_key, &identity.recipient().unwrap()).unwrap();
+        let unwrapped = unwrap(
+            std::slice::from_ref(&stanza),
+            identity.as_bytes().try_into().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(unwrapped.as_bytes(), data_key.as_bytes());
+
+        let result = unwrap(&[stanza], other.as_bytes().try_into().unwrap());
+        assert!(result.is_err_and(|err| err.contains("not a recipient")));
+    }
+
+    // Test that every recipient can decrypt a multi-recipient vault
+    #[test]
+    fn test_encrypt_multiple_recipients() {
+        let alice = Key::generate_identity();
+        let bob = Key::generate_identity();
+        let eve = Key::generate_identity();
+        let pack = EnvironmentPack::File(b"A=1".to_vec());
+
+        let recipients = vec![alice.recipient().unwrap(), bob.recipient().unwrap()];
+        let armored = encrypt_base64(&pack, &recipients).unwrap();
+
+        assert_eq!(list(&armored).unwrap(), recipients);
+        assert_eq!(
+            alice.decrypt_base64(&armored).unwrap().content().unwrap(),
+            b"A=1"
+        );
+        assert_eq!(
+            bob.decrypt_base64(&armored).unwrap().content().unwrap(),
+            b"A=1"
+        );
+        assert!(eve.decrypt_base64(&armored).is_err());
+        assert!(Key::generate().decrypt_base64(&armored).is_err());
+    }
+
+    // Test that a vault needs recipients
+    #[test]
+    fn test_encrypt_no_recipients() {
+        let pack = EnvironmentPack::File(b"A=1".to_vec());
+        assert!(encrypt_base64(&pack, &[]).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+use log::debug;
+
+/// Lock the memory of `buf` so it is not swapped to disk.
+/// Best effort: locking fails e.g. when `RLIMIT_MEMLOCK` is exhausted.
+pub fn lock(buf: &[u8]) {
+    if buf.is_empty() {
+        return;
+    }
+    #[cfg(unix)]
+    {
+        let result = unsafe { libc::mlock(buf.as_ptr().cast(), buf.len()) };
+        if result != 0 {
+            debug!(
+                "Failed to lock {} bytes of memory: {}",
+                buf.len(),
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+/// Unlock memory locked with `lock`, before it is freed
+pub fn unlock(buf: &[u8]) {
+    if buf.is_empty() {
+        return;
+    }
+    #[cfg(unix)]
+    unsafe {
+        libc::munlock(buf.as_ptr().cast(), buf.len());
+    }
+}
+
+/// Keep key material and plaintext out of core dumps if the process crashes.
+/// On Linux this does not carry over to the command started by `run`.
+pub fn disable_core_dumps() {
+    // Also prevents other processes of the user from attaching with ptrace
+    #[cfg(target_os = "linux")]
+    let result = unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0) };
+    #[cfg(all(unix, not(target_os = "linux")))]
+    let result = unsafe {
+        let mut limit = std::mem::zeroed::<libc::rlimit>();
+        libc::getrlimit(libc::RLIMIT_CORE, &mut limit);
+        limit.rlim_cur = 0;
+        libc::setrlimit(libc::RLIMIT_CORE, &limit)
+    };
+    #[cfg(unix)]
+    if result != 0 {
+        debug!(
+            "Failed to disable core dumps: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
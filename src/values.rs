@@ -0,0 +1,320 @@
+use crate::crypto::{self, Key};
+use crate::dotenv::{self, Line};
+use base64::Engine;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+/// First line of a vault whose values are encrypted individually
+pub const VALUES_MAGIC: &str = "# envbuddel-values v1";
+
+/// Prefix of the second line recording the fingerprint of the vault key
+const KEY_ID_PREFIX: &str = "# key: ";
+
+/// Name of the last line holding the MAC over all lines above it
+const MAC_NAME: &str = "ENVBUDDEL_MAC";
+
+/// Whether the vault content is a per-value vault
+pub fn is_values_vault(content: &str) -> bool {
+    content.lines().next().map(str::trim_end) == Some(VALUES_MAGIC)
+}
+
+/// Fingerprint of the key recorded in a per-value vault
+pub fn key_fingerprint(content: &str) -> Option<&str> {
+    content
+        .lines()
+        .nth(1)?
+        .trim_end()
+        .strip_prefix(KEY_ID_PREFIX)
+}
+
+/// Derive independent keys for values and MAC from the vault key
+fn subkey(key: &Key, info: &[u8]) -> Key {
+    let mut okm = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(None, key.as_bytes())
+        .expand(info, &mut *okm)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    Key::from_bytes(&*okm).expect("HKDF output has key length")
+}
+
+fn value_key(key: &Key) -> Key {
+    subkey(key, b"envbuddel-values-v1-value")
+}
+
+fn mac_key(key: &Key) -> Key {
+    subkey(key, b"envbuddel-values-v1-mac")
+}
+
+/// Encrypt every value of the dotenv content individually while names and comments stay readable.
+/// Unchanged values and an unchanged file keep the ciphertext of `previous` so diffs stay small.
+pub fn encrypt(key: &Key, content: &[u8], previous: Option<&str>) -> Result<String, String> {
+    if key.recipient().is_some() {
+        return Err("Per-value vaults need a symmetric key, not an identity".to_string());
+    }
+    let content = std::str::from_utf8(content)
+        .map_err(|e| format!("Environment is not valid UTF-8: {}", e))?;
+
+    let previous = previous.and_then(|previous| decrypt_entries(key, previous).ok());
+    let reusable: Vec<&(String, Zeroizing<String>, String)> = previous
+        .iter()
+        .flat_map(|(entries, _)| entries.iter())
+        .collect();
+
+    let b64 = base64::engine::general_purpose::STANDARD;
+    let value_key = value_key(key);
+    let mut lines = vec![
+        VALUES_MAGIC.to_string(),
+        format!("{}{}", KEY_ID_PREFIX, key.fingerprint()),
+    ];
+    for line in dotenv::lines(content)? {
+        match line {
+            Line::Other(text) => lines.push(text.to_string()),
+            Line::Variable {
+                key: name,
+                prefix,
+                raw_value,
+            } => {
+                if name == MAC_NAME {
+                    return Err(format!("{} is reserved by envbuddel", MAC_NAME));
+                }
+                // Any previous ciphertext of the same name and value decrypts the same way
+                let ciphertext = match reusable.iter().find(|(previous_name, previous_value, _)| {
+                    previous_name == name && previous_value.as_str() == raw_value
+                }) {
+                    Some((_, _, ciphertext)) => ciphertext.to_string(),
+                    // The name is authenticated so values cannot be swapped between variables
+                    None => b64.encode(value_key.seal(raw_value.as_bytes(), name.as_bytes())?),
+                };
+                lines.push(format!("{}ENC[{}]", prefix, ciphertext));
+            }
+        }
+    }
+
+    // A final line break ends the body, so the vault separates it from the MAC line by a blank line
+    let mut body = lines.join("\n");
+    if content.ends_with('\n') {
+        body.push('\n');
+    }
+    let mac = match &previous {
+        Some((_, (previous_body, mac))) if *previous_body == body => mac.clone(),
+        _ => b64.encode(mac_key(key).seal(&[], body.as_bytes())?),
+    };
+    Ok(format!("{}\n{}={}\n", body, MAC_NAME, mac))
+}
+
+/// Decrypt a per-value vault back to the original dotenv content
+pub fn decrypt(key: &Key, content: &str) -> Result<Zeroizing<Vec<u8>>, String> {
+    let (entries, _) = decrypt_entries(key, content)?;
+    // The entries are in line order, so duplicate names keep their own values
+    let mut values = entries.iter();
+
+    let (body, _) = split_mac(content)?;
+    let mut plaintext = Zeroizing::new(String::new());
+    // Skip the magic and key lines
+    for (index, line) in dotenv::lines(body)?.into_iter().skip(2).enumerate() {
+        if index > 0 {
+            plaintext.push('\n');
+        }
+        match line {
+            Line::Other(text) => plaintext.push_str(text),
+            Line::Variable { key, prefix, .. } => {
+                let (_, value, _) = values
+                    .next()
+                    .filter(|(name, _, _)| name == key)
+                    .ok_or_else(|| format!("Vault is corrupted: no value for {}", key))?;
+                plaintext.push_str(prefix);
+                plaintext.push_str(value);
+            }
+        }
+    }
+    if body.ends_with('\n') {
+        plaintext.push('\n');
+    }
+
+    Ok(Zeroizing::new(std::mem::take(&mut *plaintext).into_bytes()))
+}
+
+/// Verify the MAC and decrypt every value.
+/// Returns `(name, raw value, ciphertext)` per variable and `(body, mac)` of the file.
+#[allow(clippy::type_complexity)]
+fn decrypt_entries(
+    key: &Key,
+    content: &str,
+) -> Result<(Vec<(String, Zeroizing<String>, String)>, (String, String)), String> {
+    if !is_values_vault(content) {
+        return Err("Not a per-value vault".to_string());
+    }
+    if key.recipient().is_some() {
+        return Err("Per-value vaults need a symmetric key, not an identity".to_string());
+    }
+
+    let fingerprint = key_fingerprint(content).ok_or("Per-value vault is missing its key line")?;
+    if fingerprint != key.fingerprint() {
+        return Err(crypto::wrong_key(fingerprint, key));
+    }
+
+    let b64 = base64::engine::general_purpose::STANDARD;
+    let (body, mac) = split_mac(content)?;
+    let mac_bytes = b64
+        .decode(mac)
+        .map_err(|e| format!("Failed to decode {}: {}", MAC_NAME, e))?;
+    mac_key(key).open(&mac_bytes, body.as_bytes()).map_err(|_| {
+        format!(
+            "Vault is corrupted: MAC verification failed although key {} matches the vault. Entries were modified, reordered or removed.",
+            fingerprint
+        )
+    })?;
+
+    let value_key = value_key(key);
+    let mut entries = Vec::new();
+    for line in dotenv::lines(body)? {
+        if let Line::Variable { key, raw_value, .. } = line {
+            let ciphertext = raw_value
+                .trim()
+                .strip_prefix("ENC[")
+                .and_then(|rest| rest.strip_suffix(']'))
+                .ok_or_else(|| format!("Value of {} is not encrypted", key))?;
+            let bytes = b64
+                .decode(ciphertext)
+                .map_err(|e| format!("Failed to decode value of {}: {}", key, e))?;
+            let plaintext = value_key
+                .open(&bytes, key.as_bytes())
+                .map_err(|_| format!("Failed to decrypt value of {}", key))?;
+            let plaintext = std::str::from_utf8(&plaintext)
+                .map(|value| Zeroizing::new(value.to_string()))
+                .map_err(|e| format!("Value of {} is not valid UTF-8: {}", key, e))?;
+            entries.push((key.to_string(), plaintext, ciphertext.to_string()));
+        }
+    }
+
+    Ok((entries, (body.to_string(), mac.to_string())))
+}
+
+/// Split the content into the authenticated body and the MAC of the last line
+fn split_mac(content: &str) -> Result<(&str, &str), String> {
+    let trimmed = content.trim_end();
+    let (body, last) = trimmed.rsplit_once('\n').unwrap_or(("", trimmed));
+    let mac = last
+        .strip_prefix(MAC_NAME)
+        .and_then(|rest| rest.strip_prefix('='))
+        .ok_or_else(|| format!("Per-value vault is missing its {} line", MAC_NAME))?;
+    Ok((body.trim_end_matches('\r'), mac.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOTENV: &str = "# database\nDB_USER=admin\nDB_PASS = 'secret'\n\nexport TOKEN=abc # inline\nCERT=\"line 1\nline 2\"\n";
+
+    // Test roundtrip keeps the file byte for byte
+    #[test]
+    fn test_roundtrip() {
+        let key = Key::generate();
+        let vault = encrypt(&key, DOTENV.as_bytes(), None).unwrap();
+        assert!(is_values_vault(&vault));
+        assert!(vault.contains("\nDB_USER=ENC["));
+        assert!(vault.contains("\n# database\n"));
+        assert!(vault.contains("\nexport TOKEN=ENC["));
+        assert!(!vault.contains("secret"));
+
+        assert_eq!(*decrypt(&key, &vault).unwrap(), DOTENV.as_bytes());
+    }
+
+    // Test that content without a final line break or without any line keeps it that way
+    #[test]
+    fn test_final_newline() {
+        let key = Key::generate();
+        for content in ["A=1\nB=2", "A=1\n\n", "", "\n"] {
+            let vault = encrypt(&key, content.as_bytes(), None).unwrap();
+            assert_eq!(*decrypt(&key, &vault).unwrap(), content.as_bytes());
+        }
+    }
+
+    // Test that only the changed variable and the MAC differ after re-encryption
+    #[test]
+    fn test_unchanged_values_are_stable() {
+        let key = Key::generate();
+        let first = encrypt(&key, DOTENV.as_bytes(), None).unwrap();
+        assert_eq!(
+            encrypt(&key, DOTENV.as_bytes(), Some(&first)).unwrap(),
+            first
+        );
+
+        let changed = DOTENV.replace("DB_USER=admin", "DB_USER=root");
+        let second = encrypt(&key, changed.as_bytes(), Some(&first)).unwrap();
+        let differing: Vec<_> = first
+            .lines()
+            .zip(second.lines())
+            .filter(|(a, b)| a != b)
+            .map(|(a, _)| a.split('=').next().unwrap().to_string())
+            .collect();
+        assert_eq!(differing, vec!["DB_USER", MAC_NAME]);
+        assert_eq!(*decrypt(&key, &second).unwrap(), changed.as_bytes());
+    }
+
+    // Test that a name defined twice keeps both values through decrypt and re-encryption
+    #[test]
+    fn test_duplicate_name() {
+        let key = Key::generate();
+        let content = b"A=1\nB=x\nA=2\n";
+        let vault = encrypt(&key, content, None).unwrap();
+        assert_eq!(*decrypt(&key, &vault).unwrap(), content);
+
+        let changed = b"A=1\nB=x\nA=3\n";
+        let second = encrypt(&key, changed, Some(&vault)).unwrap();
+        assert_eq!(*decrypt(&key, &second).unwrap(), changed);
+        assert_eq!(vault.lines().nth(2), second.lines().nth(2));
+    }
+
+    // Test that reordering or dropping entries is detected
+    #[test]
+    fn test_tampering_is_detected() {
+        let key = Key::generate();
+        let vault = encrypt(&key, b"A=1\nB=2\n", None).unwrap();
+        let lines: Vec<&str> = vault.lines().collect();
+
+        let reordered = [lines[0], lines[1], lines[3], lines[2], lines[4], lines[5]].join("\n");
+        assert!(decrypt(&key, &reordered).is_err());
+
+        let dropped = [lines[0], lines[1], lines[3], lines[4], lines[5]].join("\n");
+        assert!(decrypt(&key, &dropped).is_err());
+
+        let swapped = vault.replace(
+            lines[2].split_once('=').unwrap().1,
+            lines[3].split_once('=').unwrap().1,
+        );
+        assert!(decrypt(&key, &swapped).is_err());
+    }
+
+    // Test that the key fingerprint is recorded and vaults without it are rejected
+    #[test]
+    fn test_key_fingerprint() {
+        let key = Key::generate();
+        let vault = encrypt(&key, b"A=1\n", None).unwrap();
+        assert_eq!(key_fingerprint(&vault), Some(key.fingerprint().as_str()));
+
+        let mut lines: Vec<&str> = vault.lines().collect();
+        lines.remove(1);
+        let body = lines[..lines.len() - 1].join("\n");
+        let b64 = base64::engine::general_purpose::STANDARD;
+        let mac = b64.encode(mac_key(&key).seal(&[], body.as_bytes()).unwrap());
+        let legacy = format!("{}\n{}={}\n", body, MAC_NAME, mac);
+        assert_eq!(key_fingerprint(&legacy), None);
+        assert!(decrypt(&key, &legacy).is_err());
+    }
+
+    // Test that a wrong key is rejected
+    #[test]
+    fn test_wrong_key() {
+        let vault = encrypt(&Key::generate(), b"A=1\n", None).unwrap();
+        assert!(decrypt(&Key::generate(), &vault).is_err());
+    }
+
+    // Test that the MAC name cannot be used as variable
+    #[test]
+    fn test_reserved_name() {
+        let result = encrypt(&Key::generate(), b"ENVBUDDEL_MAC=1\n", None);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,421 @@
+use crate::values;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Magic bytes every versioned vault starts with
+pub const MAGIC: &[u8; 4] = b"EBDL";
+
+/// Newest vault format version this build can read and the one it writes
+pub const FORMAT_VERSION: u8 = 1;
+
+/// Length of the fixed header: magic, version, cipher, kdf and flags (u16 LE)
+pub const HEADER_LEN: usize = MAGIC.len() + 1 + 1 + 1 + 2;
+
+/// Length of a data key wrapped for a recipient: nonce, key and tag
+pub const WRAPPED_KEY_LEN: usize = 12 + 32 + 16;
+
+/// Length of a serialized recipient stanza
+const STANZA_LEN: usize = 32 + 32 + WRAPPED_KEY_LEN;
+
+/// Length of the key id identifying the key of a symmetric vault
+pub const KEY_ID_LEN: usize = 8;
+
+/// Flag: the fixed header is followed by the key id
+const FLAG_KEY_ID: u16 = 0x0001;
+
+/// Bitmask of all flags known to this build
+const KNOWN_FLAGS: u16 = FLAG_KEY_ID;
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Cipher {
+    Aes256Gcm,
+}
+
+impl Cipher {
+    fn id(&self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self, String> {
+        match id {
+            1 => Ok(Cipher::Aes256Gcm),
+            _ => Err(format!(
+                "Vault uses unknown cipher id {}. Please upgrade envbuddel.",
+                id
+            )),
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Kdf {
+    /// The key is used directly as cipher key
+    None,
+    /// A random data key is wrapped for every recipient with X25519 and HKDF-SHA256
+    X25519,
+}
+
+impl Kdf {
+    fn id(&self) -> u8 {
+        match self {
+            Kdf::None => 0,
+            Kdf::X25519 => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self, String> {
+        match id {
+            0 => Ok(Kdf::None),
+            1 => Ok(Kdf::X25519),
+            _ => Err(format!(
+                "Vault uses unknown key derivation id {}. Please upgrade envbuddel.",
+                id
+            )),
+        }
+    }
+}
+
+/// The data key of a vault wrapped for a single recipient
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Stanza {
+    /// X25519 public key of the recipient
+    pub recipient: [u8; 32],
+    /// Ephemeral X25519 public key used for the key agreement
+    pub ephemeral: [u8; 32],
+    /// Data key encrypted with the shared secret
+    pub wrapped_key: [u8; WRAPPED_KEY_LEN],
+}
+
+/// Header in front of the ciphertext of a versioned vault.
+/// The serialized header is authenticated as associated data of the cipher.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct VaultHeader {
+    pub version: u8,
+    pub cipher: Cipher,
+    pub kdf: Kdf,
+    pub flags: u16,
+    /// Id of the key the vault is encrypted with, see `Key::key_id`
+    pub key_id: Option<[u8; KEY_ID_LEN]>,
+    /// Recipient stanzas, only present with `Kdf::X25519`
+    pub stanzas: Vec<Stanza>,
+}
+
+impl Default for VaultHeader {
+    fn default() -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            cipher: Cipher::Aes256Gcm,
+            kdf: Kdf::None,
+            flags: 0,
+            key_id: None,
+            stanzas: Vec::new(),
+        }
+    }
+}
+
+impl VaultHeader {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(self.version);
+        bytes.push(self.cipher.id());
+        bytes.push(self.kdf.id());
+        let flags = match self.key_id {
+            Some(_) => self.flags | FLAG_KEY_ID,
+            None => self.flags & !FLAG_KEY_ID,
+        };
+        bytes.extend_from_slice(&flags.to_le_bytes());
+        if let Some(key_id) = &self.key_id {
+            bytes.extend_from_slice(key_id);
+        }
+        if self.kdf == Kdf::X25519 {
+            bytes.extend_from_slice(&(self.stanzas.len() as u16).to_le_bytes());
+            for stanza in &self.stanzas {
+                bytes.extend_from_slice(&stanza.recipient);
+                bytes.extend_from_slice(&stanza.ephemeral);
+                bytes.extend_from_slice(&stanza.wrapped_key);
+            }
+        }
+        bytes
+    }
+
+    /// Parse the header in front of `bytes`.
+    /// Returns `None` if the data does not start with the magic bytes, i.e. it is a headerless legacy vault.
+    /// Otherwise returns the header and the remaining body.
+    /// The serialized header is `bytes[..bytes.len() - body.len()]`.
+    pub fn parse(bytes: &[u8]) -> Result<Option<(VaultHeader, &[u8])>, String> {
+        if !bytes.starts_with(MAGIC) {
+            return Ok(None);
+        }
+        if bytes.len() < HEADER_LEN {
+            return Err("Vault header is truncated".to_string());
+        }
+
+        let version = bytes[4];
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(format!(
+                "Vault format version {} is not supported by this envbuddel (supports up to version {}). Please upgrade envbuddel.",
+                version, FORMAT_VERSION
+            ));
+        }
+
+        let cipher = Cipher::from_id(bytes[5])?;
+        let kdf = Kdf::from_id(bytes[6])?;
+        let flags = u16::from_le_bytes([bytes[7], bytes[8]]);
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(format!(
+                "Vault uses unknown flags {:#06x}. Please upgrade envbuddel.",
+                flags & !KNOWN_FLAGS
+            ));
+        }
+
+        let mut rest = &bytes[HEADER_LEN..];
+        let mut key_id = None;
+        if flags & FLAG_KEY_ID != 0 {
+            if rest.len() < KEY_ID_LEN {
+                return Err("Vault header is truncated".to_string());
+            }
+            let mut id = [0u8; KEY_ID_LEN];
+            id.copy_from_slice(&rest[..KEY_ID_LEN]);
+            key_id = Some(id);
+            rest = &rest[KEY_ID_LEN..];
+        }
+
+        let mut stanzas = Vec::new();
+        if kdf == Kdf::X25519 {
+            if rest.len() < 2 {
+                return Err("Vault header is truncated".to_string());
+            }
+            let count = u16::from_le_bytes([rest[0], rest[1]]) as usize;
+            rest = &rest[2..];
+            if rest.len() < count * STANZA_LEN {
+                return Err("Vault header is truncated".to_string());
+            }
+            for chunk in rest[..count * STANZA_LEN].chunks(STANZA_LEN) {
+                let mut stanza = Stanza {
+                    recipient: [0u8; 32],
+                    ephemeral: [0u8; 32],
+                    wrapped_key: [0u8; WRAPPED_KEY_LEN],
+                };
+                stanza.recipient.copy_from_slice(&chunk[..32]);
+                stanza.ephemeral.copy_from_slice(&chunk[32..64]);
+                stanza.wrapped_key.copy_from_slice(&chunk[64..]);
+                stanzas.push(stanza);
+            }
+            rest = &rest[count * STANZA_LEN..];
+        }
+
+        Ok(Some((
+            VaultHeader {
+                version,
+                cipher,
+                kdf,
+                flags,
+                key_id,
+                stanzas,
+            },
+            rest,
+        )))
+    }
+}
+
+impl fmt::Display for VaultHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cipher = match self.cipher {
+            Cipher::Aes256Gcm => "AES-256-GCM",
+        };
+        let kdf = match self.kdf {
+            Kdf::None => "none",
+            Kdf::X25519 => "X25519 recipients",
+        };
+        write!(
+            f,
+            "format v{}, cipher {}, key derivation {}",
+            self.version, cipher, kdf
+        )?;
+        if let Some(key_id) = &self.key_id {
+            write!(f, ", key {}", fingerprint(key_id))?;
+        }
+        Ok(())
+    }
+}
+
+/// How the environment is stored in the vault
+#[derive(Eq, PartialEq, Debug, Clone, Copy, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VaultMode {
+    /// The whole environment is encrypted as one Base64 blob
+    Blob,
+    /// Every value of a dotenv file is encrypted individually, names stay readable
+    Values,
+}
+
+impl VaultMode {
+    /// Detect the mode of existing vault content
+    pub fn detect(content: &str) -> Self {
+        if values::is_values_vault(content) {
+            VaultMode::Values
+        } else {
+            VaultMode::Blob
+        }
+    }
+}
+
+/// Read the header of an armored vault without decrypting it.
+/// Returns `None` for headerless legacy vaults.
+pub fn inspect(armored: &str) -> Result<Option<VaultHeader>, String> {
+    let bytes = decode_armor(armored)?;
+    Ok(VaultHeader::parse(&bytes)?.map(|(header, _)| header))
+}
+
+/// Printable form of a key id
+pub fn fingerprint(key_id: &[u8; KEY_ID_LEN]) -> String {
+    key_id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Fingerprint of the key recorded in the vault, without decrypting it.
+/// Returns `None` for vaults encrypted to recipients and vaults written before key ids were recorded.
+pub fn key_fingerprint(content: &str) -> Result<Option<String>, String> {
+    if values::is_values_vault(content) {
+        return Ok(values::key_fingerprint(content).map(str::to_string));
+    }
+    Ok(inspect(content)?
+        .and_then(|header| header.key_id)
+        .map(|key_id| fingerprint(&key_id)))
+}
+
+/// Encode binary vault data as Base64 wrapped at 64 chars
+pub fn encode_armor(bytes: &[u8]) -> String {
+    let b64 = base64::engine::general_purpose::STANDARD.encode(bytes);
+
+    // Wrap lines manually at 64 chars
+    b64.chars()
+        .collect::<Vec<_>>()
+        .chunks(64)
+        .map(|chunk| chunk.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Decode Base64 armored vault data
+pub fn decode_armor(armored: &str) -> Result<Vec<u8>, String> {
+    // Remove any line breaks (LF or CRLF) before decoding
+    let cleaned = armored.replace(&['\n', '\r'][..], "");
+
+    base64::engine::general_purpose::STANDARD
+        .decode(cleaned)
+        .map_err(|e| format!("Failed to decode Base64 ciphertext: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test header serialization roundtrip
+    #[test]
+    fn test_header_roundtrip() {
+        let header = VaultHeader::default();
+        let mut bytes = header.to_bytes();
+        bytes.extend_from_slice(b"body");
+
+        let (parsed, body) = VaultHeader::parse(&bytes).unwrap().unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(body, b"body");
+    }
+
+    // Test that data without magic is treated as legacy vault
+    #[test]
+    fn test_parse_legacy() {
+        assert!(VaultHeader::parse(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10])
+            .unwrap()
+            .is_none());
+    }
+
+    // Test that unknown versions are rejected with a clear error
+    #[test]
+    fn test_parse_unknown_version() {
+        let mut bytes = VaultHeader::default().to_bytes();
+        bytes[4] = FORMAT_VERSION + 1;
+        let err = VaultHeader::parse(&bytes).unwrap_err();
+        assert!(err.contains("not supported"));
+    }
+
+    // Test that unknown cipher ids and flags are rejected
+    #[test]
+    fn test_parse_unknown_cipher_and_flags() {
+        let mut bytes = VaultHeader::default().to_bytes();
+        bytes[5] = 99;
+        assert!(VaultHeader::parse(&bytes).is_err());
+
+        let mut bytes = VaultHeader::default().to_bytes();
+        bytes[8] = 0x80;
+        assert!(VaultHeader::parse(&bytes).is_err());
+    }
+
+    // Test header roundtrip with recipient stanzas
+    #[test]
+    fn test_header_roundtrip_stanzas() {
+        let header = VaultHeader {
+            kdf: Kdf::X25519,
+            stanzas: vec![
+                Stanza {
+                    recipient: [1u8; 32],
+                    ephemeral: [2u8; 32],
+                    wrapped_key: [3u8; WRAPPED_KEY_LEN],
+                },
+                Stanza {
+                    recipient: [4u8; 32],
+                    ephemeral: [5u8; 32],
+                    wrapped_key: [6u8; WRAPPED_KEY_LEN],
+                },
+            ],
+            ..VaultHeader::default()
+        };
+        let mut bytes = header.to_bytes();
+        bytes.extend_from_slice(b"body");
+
+        let (parsed, body) = VaultHeader::parse(&bytes).unwrap().unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(body, b"body");
+
+        assert!(VaultHeader::parse(&bytes[..HEADER_LEN + 10]).is_err());
+    }
+
+    // Test header roundtrip with a key id
+    #[test]
+    fn test_header_roundtrip_key_id() {
+        let header = VaultHeader {
+            key_id: Some([0xab; KEY_ID_LEN]),
+            ..VaultHeader::default()
+        };
+        let mut bytes = header.to_bytes();
+        assert_eq!(bytes.len(), HEADER_LEN + KEY_ID_LEN);
+        bytes.extend_from_slice(b"body");
+
+        let (parsed, body) = VaultHeader::parse(&bytes).unwrap().unwrap();
+        assert_eq!(parsed.key_id, header.key_id);
+        assert_eq!(parsed.flags, FLAG_KEY_ID);
+        assert_eq!(body, b"body");
+        assert!(parsed.to_string().ends_with("key abababababababab"));
+
+        assert!(VaultHeader::parse(&bytes[..HEADER_LEN + 2]).is_err());
+    }
+
+    // Test that a truncated header is rejected
+    #[test]
+    fn test_parse_truncated() {
+        assert!(VaultHeader::parse(MAGIC).is_err());
+    }
+
+    // Test Base64 armor roundtrip with line wrapping
+    #[test]
+    fn test_armor_roundtrip() {
+        let data = vec![7u8; 200];
+        let armored = encode_armor(&data);
+        assert!(armored.lines().all(|line| line.len() <= 64));
+        assert_eq!(decode_armor(&armored).unwrap(), data);
+    }
+}